@@ -1,25 +1,97 @@
-use crate::EnvVar;
-
-use std::path::PathBuf;
+use crate::detect::{Bwrap, BwrapFeature, BWRAP_DEFAULT_PATH};
+use crate::fd::{self, DataFd};
+use crate::landlock::LandlockRuleset;
+use crate::native::{self, NativeOptions};
+use crate::oci::{self, OciBundle, OciOptions};
+use crate::seccomp::SeccompPolicy;
+use crate::status::SandboxStatus;
+use crate::systemd::SystemdScope;
+use crate::validate::{self, Diagnostic};
+use crate::{resolve_env, EnvVar, Result, Rlimit, SandboxError};
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 #[derive(Debug, Clone)]
 pub enum BubMount {
-    DevBind { src: PathBuf, dest: PathBuf },
-
-    BindRO { src: PathBuf, dest: PathBuf },
-    BindRW { src: PathBuf, dest: PathBuf },
-
-    Symlink { src: PathBuf, dest: PathBuf },
-
-    TmpFs { path: PathBuf },
-    Proc { path: PathBuf },
-
-    Dir { path: PathBuf },
-    File { content: String, path: PathBuf },
+    DevBind {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+
+    BindRO {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+    BindRW {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+
+    Symlink {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+
+    TmpFs {
+        path: PathBuf,
+        perms: Option<u32>,
+        size: Option<u64>,
+    },
+    Proc {
+        path: PathBuf,
+    },
+
+    Dir {
+        path: PathBuf,
+        perms: Option<u32>,
+    },
+    File {
+        content: String,
+        path: PathBuf,
+        perms: Option<u32>,
+    },
+    BindData {
+        content: String,
+        path: PathBuf,
+        perms: Option<u32>,
+    },
+    BindDataRO {
+        content: String,
+        path: PathBuf,
+        perms: Option<u32>,
+    },
+
+    Overlay {
+        sources: Vec<PathBuf>,
+        upper: PathBuf,
+        work: PathBuf,
+        dest: PathBuf,
+    },
+    TmpOverlay {
+        sources: Vec<PathBuf>,
+        dest: PathBuf,
+    },
+    RoOverlay {
+        sources: Vec<PathBuf>,
+        dest: PathBuf,
+    },
+
+    Chmod {
+        mode: u32,
+        path: PathBuf,
+    },
+    RemountRO {
+        path: PathBuf,
+    },
 }
 
-#[allow(dead_code)]
 impl BubMount {
     /// A dev_bind allows the sandbox to access the device, not just the filesystem.
     pub fn dev_bind<T: Into<PathBuf>, E: Into<PathBuf>>(src: T, dest: E) -> BubMount {
@@ -44,7 +116,11 @@ impl BubMount {
     }
 
     pub fn tmpfs<T: Into<PathBuf>>(dest: T) -> BubMount {
-        BubMount::TmpFs { path: dest.into() }
+        BubMount::TmpFs {
+            path: dest.into(),
+            perms: None,
+            size: None,
+        }
     }
 
     pub fn proc<T: Into<PathBuf>>(dest: T) -> BubMount {
@@ -59,28 +135,322 @@ impl BubMount {
     }
 
     pub fn dir<T: Into<PathBuf>>(path: T) -> BubMount {
-        BubMount::Dir { path: path.into() }
+        BubMount::Dir {
+            path: path.into(),
+            perms: None,
+        }
     }
 
+    /// Creates a file with the given content, which the sandbox is free to replace
     pub fn file<T: Into<String>, P: Into<PathBuf>>(content: T, path: P) -> BubMount {
         BubMount::File {
             content: content.into(),
             path: path.into(),
+            perms: None,
         }
     }
 
-    fn apply_arg(&self, cmd: &mut Command) {
+    /// Mounts the content as a file, which the sandbox can write to but not replace
+    pub fn bind_data<T: Into<String>, P: Into<PathBuf>>(content: T, path: P) -> BubMount {
+        BubMount::BindData {
+            content: content.into(),
+            path: path.into(),
+            perms: None,
+        }
+    }
+
+    pub fn bind_data_ro<T: Into<String>, P: Into<PathBuf>>(content: T, path: P) -> BubMount {
+        BubMount::BindDataRO {
+            content: content.into(),
+            path: path.into(),
+            perms: None,
+        }
+    }
+
+    /// Stacks the sources on top of each other, with `upper` on top where all writes end up.
+    /// `work` has to be an empty directory on the same filesystem as `upper`.
+    pub fn overlay<S, T, W, E>(sources: S, upper: T, work: W, dest: E) -> BubMount
+    where
+        S: IntoIterator,
+        S::Item: Into<PathBuf>,
+        T: Into<PathBuf>,
+        W: Into<PathBuf>,
+        E: Into<PathBuf>,
+    {
+        BubMount::Overlay {
+            sources: sources.into_iter().map(Into::into).collect(),
+            upper: upper.into(),
+            work: work.into(),
+            dest: dest.into(),
+        }
+    }
+
+    /// Like [`BubMount::overlay`], but writes go to a tmpfs and are gone once the sandbox exits
+    pub fn tmp_overlay<S, E>(sources: S, dest: E) -> BubMount
+    where
+        S: IntoIterator,
+        S::Item: Into<PathBuf>,
+        E: Into<PathBuf>,
+    {
+        BubMount::TmpOverlay {
+            sources: sources.into_iter().map(Into::into).collect(),
+            dest: dest.into(),
+        }
+    }
+
+    pub fn ro_overlay<S, E>(sources: S, dest: E) -> BubMount
+    where
+        S: IntoIterator,
+        S::Item: Into<PathBuf>,
+        E: Into<PathBuf>,
+    {
+        BubMount::RoOverlay {
+            sources: sources.into_iter().map(Into::into).collect(),
+            dest: dest.into(),
+        }
+    }
+
+    /// Changes the permissions of something which already exists in the sandbox
+    pub fn chmod<T: Into<PathBuf>>(mode: u32, path: T) -> BubMount {
+        BubMount::Chmod {
+            mode,
+            path: path.into(),
+        }
+    }
+
+    /// Makes an earlier mount read only, including the mounts below it
+    pub fn remount_ro<T: Into<PathBuf>>(path: T) -> BubMount {
+        BubMount::RemountRO { path: path.into() }
+    }
+
+    /// Sets the permissions of what the mount creates, such as `0o700` for a private tmpfs.
+    /// Mounts which don't create anything are left as they are.
+    pub fn perms(mut self, mode: u32) -> BubMount {
+        match &mut self {
+            BubMount::TmpFs { perms, .. }
+            | BubMount::Dir { perms, .. }
+            | BubMount::File { perms, .. }
+            | BubMount::BindData { perms, .. }
+            | BubMount::BindDataRO { perms, .. } => *perms = Some(mode),
+            _ => (),
+        }
+
+        self
+    }
+
+    /// Caps the size of a tmpfs in bytes, other mounts are left as they are
+    pub fn size(mut self, bytes: u64) -> BubMount {
+        if let BubMount::TmpFs { size, .. } = &mut self {
+            *size = Some(bytes);
+        }
+
+        self
+    }
+
+    /// The optional bwrap features needed for this mount
+    pub fn features(&self) -> Vec<BwrapFeature> {
+        let mut features = Vec::new();
+
+        match self {
+            BubMount::TmpFs { perms, size, .. } => {
+                if perms.is_some() {
+                    features.push(BwrapFeature::Perms);
+                }
+                if size.is_some() {
+                    features.push(BwrapFeature::Size);
+                }
+            }
+            BubMount::Dir { perms: Some(_), .. } | BubMount::File { perms: Some(_), .. } => {
+                features.push(BwrapFeature::Perms)
+            }
+            BubMount::BindData { perms, .. } | BubMount::BindDataRO { perms, .. } => {
+                features.push(BwrapFeature::BindData);
+                if perms.is_some() {
+                    features.push(BwrapFeature::Perms);
+                }
+            }
+            BubMount::Overlay { .. } | BubMount::TmpOverlay { .. } | BubMount::RoOverlay { .. } => {
+                features.push(BwrapFeature::Overlay)
+            }
+            BubMount::Chmod { .. } => features.push(BwrapFeature::Chmod),
+            BubMount::RemountRO { .. } => features.push(BwrapFeature::RemountRo),
+            _ => (),
+        }
+
+        features
+    }
+
+    fn apply_arg(self, cmd: &mut Command, fds: &mut Vec<File>) -> Result<()> {
         match self {
             BubMount::DevBind { src, dest } => cmd.arg("--dev-bind").arg(src).arg(dest),
             BubMount::BindRO { src, dest } => cmd.arg("--ro-bind").arg(src).arg(dest),
             BubMount::BindRW { src, dest } => cmd.arg("--bind").arg(src).arg(dest),
             BubMount::Symlink { src, dest } => cmd.arg("--symlink").arg(src).arg(dest),
-            BubMount::TmpFs { path } => cmd.arg("--tmpfs").arg(path),
+            BubMount::TmpFs { path, perms, size } => {
+                perms_arg(cmd, perms);
+                if let Some(size) = size {
+                    cmd.arg("--size").arg(size.to_string());
+                }
+                cmd.arg("--tmpfs").arg(path)
+            }
             BubMount::Proc { path } => cmd.arg("--proc").arg(path),
 
-            BubMount::Dir { path } => cmd.arg("--dir").arg(path),
-            BubMount::File { content, path } => cmd.arg("--file").arg(content).arg(path),
+            BubMount::Dir { path, perms } => perms_arg(cmd, perms).arg("--dir").arg(path),
+            BubMount::File {
+                content,
+                path,
+                perms,
+            } => data_arg(perms_arg(cmd, perms), fds, "--file", content, path)?,
+            BubMount::BindData {
+                content,
+                path,
+                perms,
+            } => data_arg(perms_arg(cmd, perms), fds, "--bind-data", content, path)?,
+            BubMount::BindDataRO {
+                content,
+                path,
+                perms,
+            } => data_arg(perms_arg(cmd, perms), fds, "--ro-bind-data", content, path)?,
+
+            BubMount::Overlay {
+                sources,
+                upper,
+                work,
+                dest,
+            } => overlay_sources(cmd, sources)
+                .arg("--overlay")
+                .arg(upper)
+                .arg(work)
+                .arg(dest),
+            BubMount::TmpOverlay { sources, dest } => {
+                overlay_sources(cmd, sources).arg("--tmp-overlay").arg(dest)
+            }
+            BubMount::RoOverlay { sources, dest } => {
+                overlay_sources(cmd, sources).arg("--ro-overlay").arg(dest)
+            }
+
+            BubMount::Chmod { mode, path } => {
+                cmd.arg("--chmod").arg(format!("{:04o}", mode)).arg(path)
+            }
+            BubMount::RemountRO { path } => cmd.arg("--remount-ro").arg(path),
         };
+
+        Ok(())
+    }
+}
+
+/// `--perms` applies to the mount which comes right after it
+fn perms_arg(cmd: &mut Command, perms: Option<u32>) -> &mut Command {
+    if let Some(mode) = perms {
+        cmd.arg("--perms").arg(format!("{:04o}", mode));
+    }
+
+    cmd
+}
+
+/// bwrap has no options for these, so they are set before it starts and the sandbox inherits them
+fn set_rlimits(cmd: &mut Command, rlimits: Vec<Rlimit>) {
+    if rlimits.is_empty() {
+        return;
+    }
+
+    unsafe {
+        cmd.pre_exec(move || {
+            for limit in &rlimits {
+                let value = libc::rlimit {
+                    rlim_cur: limit.value() as libc::rlim_t,
+                    rlim_max: limit.value() as libc::rlim_t,
+                };
+                if libc::setrlimit(limit.resource() as _, &value) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            Ok(())
+        });
+    }
+}
+
+fn overlay_sources(cmd: &mut Command, sources: Vec<PathBuf>) -> &mut Command {
+    sources.into_iter().for_each(|x| {
+        cmd.arg("--overlay-src").arg(x);
+    });
+
+    cmd
+}
+
+/// bwrap reads the content of data mounts from a file descriptor, which gets added to `fds` so it
+/// can be kept open until the command is spawned
+fn data_arg<'a>(
+    cmd: &'a mut Command,
+    fds: &mut Vec<File>,
+    flag: &str,
+    content: String,
+    path: PathBuf,
+) -> Result<&'a mut Command> {
+    let data = DataFd::new(&path.to_string_lossy(), content)?;
+    cmd.arg(flag).arg(data.raw().to_string()).arg(path);
+    fds.push(data.into());
+
+    Ok(cmd)
+}
+
+/// Moves all of the arguments of `cmd` into a NUL separated buffer, passed with `--args`
+fn args_through_fd(cmd: Command, fds: &mut Vec<File>) -> Result<Command> {
+    let mut buffer = Vec::new();
+    cmd.get_args().for_each(|x| {
+        buffer.extend_from_slice(x.as_bytes());
+        buffer.push(0);
+    });
+
+    let args = DataFd::new("args", buffer)?;
+    let mut outer = Command::new(cmd.get_program());
+    outer.arg("--args").arg(args.raw().to_string());
+    fds.push(args.into());
+
+    Ok(outer)
+}
+
+/// The user the sandboxed program runs as, which gets its own `/etc/passwd` and `/etc/group`
+#[derive(Debug, Clone)]
+pub struct SandboxUser {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+    pub shell: PathBuf,
+}
+
+impl SandboxUser {
+    pub fn new<T: Into<String>>(name: T, uid: u32, gid: u32) -> SandboxUser {
+        SandboxUser {
+            name: name.into(),
+            uid,
+            gid,
+            home: PathBuf::from("/home"),
+            shell: PathBuf::from("/bin/bash"),
+        }
+    }
+
+    fn passwd(&self) -> String {
+        format!(
+            "root:x:0:0:root:/root:/bin/sh\n\
+             {name}:x:{uid}:{gid}:{name}:{home}:{shell}\n\
+             nobody:x:65534:65534:nobody:/:/sbin/nologin\n",
+            name = self.name,
+            uid = self.uid,
+            gid = self.gid,
+            home = self.home.display(),
+            shell = self.shell.display(),
+        )
+    }
+
+    fn group(&self) -> String {
+        format!(
+            "root:x:0:\n{name}:x:{gid}:\nnobody:x:65534:\n",
+            name = self.name,
+            gid = self.gid,
+        )
     }
 }
 
@@ -103,14 +473,61 @@ pub struct BubLauncher {
     die_with_parent: bool,
 
     hostname: Option<String>,
-    uid: Option<usize>,
-    gid: Option<usize>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    user: Option<SandboxUser>,
+    machine_id: Option<String>,
+
+    seccomp: Option<SeccompPolicy>,
+    rlimits: Vec<Rlimit>,
+    scope: Option<SystemdScope>,
+
+    unshare_user_try: bool,
+    disable_userns: bool,
+    assert_userns_disabled: bool,
+    userns: Option<PathBuf>,
+    cap_drop: Vec<String>,
+    cap_add: Vec<String>,
+
+    args_fd: bool,
+
+    bwrap: Option<Bwrap>,
+    required: Vec<PathBuf>,
 }
 
 #[allow(dead_code)]
 impl BubLauncher {
-    pub fn command(self) -> Command {
-        let mut cmd = Command::new("/usr/bin/bwrap");
+    pub fn command(self) -> Result<Command> {
+        self.build(None)
+    }
+
+    /// Like [`BubLauncher::command`], but has bwrap report the pid and namespaces of the sandbox,
+    /// which can be read by spawning it through [`SandboxHandle`](crate::SandboxHandle)
+    pub fn command_with_status(self) -> Result<(Command, SandboxStatus)> {
+        let (status, write) = SandboxStatus::pipe()?;
+        Ok((self.build(Some(write))?, status))
+    }
+
+    fn build(mut self, status: Option<File>) -> Result<Command> {
+        self.check_features()?;
+
+        // Older versions can still take the arguments the usual way
+        let args_fd = self.args_fd && self.supports(BwrapFeature::Args);
+
+        // --info-fd only reports the pid and namespaces, but not the exit code
+        let status_flag = match self.supports(BwrapFeature::JsonStatusFd) {
+            true => "--json-status-fd",
+            false => "--info-fd",
+        };
+        if status.is_some() && status_flag == "--info-fd" {
+            self.require(BwrapFeature::InfoFd)?;
+        }
+
+        let mut cmd = match &self.bwrap {
+            Some(bwrap) => Command::new(&bwrap.path),
+            None => Command::new(BWRAP_DEFAULT_PATH),
+        };
+        let mut fds = Vec::new();
 
         // This might be totally pointless, but I wanted to get more familiar with macros
         macro_rules! bool_opt {
@@ -127,31 +544,273 @@ impl BubLauncher {
             };
         }
 
-        self.mounts.into_iter().for_each(|x| x.apply_arg(&mut cmd));
+        for mount in self.take_mounts() {
+            mount.apply_arg(&mut cmd, &mut fds)?;
+        }
 
         // Since bwrap applies arguments in the order they're passed, we have to do this before we
         // set any other env vars otherwise they're also cleared
         bool_opt!(self.inherit_env, when false "--clearenv");
 
-        self.env
-            .into_iter()
-            .map(EnvVar::to_key_value)
-            .for_each(|x| {
-                cmd.arg("--setenv").arg(x.0).arg(x.1);
-            });
+        let inherit_env = self.inherit_env;
+        let inherited = |key: &str| match inherit_env {
+            true => std::env::var(key).ok(),
+            false => None,
+        };
+
+        for (key, value) in resolve_env(self.env, inherited)? {
+            match value {
+                Some(value) => cmd.arg("--setenv").arg(key).arg(value),
+                None => cmd.arg("--unsetenv").arg(key),
+            };
+        }
 
         bool_opt!(self.unshare_user, when true "--unshare-user");
+        bool_opt!(self.unshare_user_try, when true "--unshare-user-try");
         bool_opt!(self.unshare_ipc, when true "--unshare-ipc");
         bool_opt!(self.unshare_pid, when true "--unshare-pid");
         bool_opt!(self.unshare_net, when true "--unshare-net");
         bool_opt!(self.unshare_uts, when true "--unshare-uts");
         bool_opt!(self.unshare_cgroups, when true "--unshare-cgroup");
 
+        if let Some(hostname) = self.hostname {
+            cmd.arg("--hostname").arg(hostname);
+        }
+        if let Some(uid) = self.uid {
+            cmd.arg("--uid").arg(uid.to_string());
+        }
+        if let Some(gid) = self.gid {
+            cmd.arg("--gid").arg(gid.to_string());
+        }
+
         bool_opt!(self.as_pid_1, when true "--as-pid-1");
         bool_opt!(self.new_session, when true "--new-session");
         bool_opt!(self.die_with_parent, when true "--die-with-parent");
 
-        cmd
+        if let Some(path) = self.userns {
+            let ns = File::open(path)?;
+            cmd.arg("--userns").arg(ns.as_raw_fd().to_string());
+            fds.push(ns);
+        }
+
+        bool_opt!(self.disable_userns, when true "--disable-userns");
+        bool_opt!(self.assert_userns_disabled, when true "--assert-userns-disabled");
+
+        if let Some(status) = status {
+            cmd.arg(status_flag).arg(status.as_raw_fd().to_string());
+            fds.push(status);
+        }
+
+        // bwrap handles these in order, so dropping everything and then adding some back works
+        self.cap_drop.into_iter().for_each(|x| {
+            cmd.arg("--cap-drop").arg(x);
+        });
+        self.cap_add.into_iter().for_each(|x| {
+            cmd.arg("--cap-add").arg(x);
+        });
+
+        if let Some(policy) = self.seccomp {
+            let program = DataFd::new("seccomp", policy.compile()?)?;
+            cmd.arg("--seccomp").arg(program.raw().to_string());
+            fds.push(program.into());
+        }
+
+        if args_fd {
+            cmd = args_through_fd(cmd, &mut fds)?;
+        }
+        if let Some(scope) = &self.scope {
+            cmd = scope.wrap(&cmd);
+        }
+
+        set_rlimits(&mut cmd, self.rlimits);
+        fd::pass_fds(&mut cmd, fds);
+        Ok(cmd)
+    }
+
+    /// Sets the sandbox up without bwrap, with the same namespaces and mounts it would use, which
+    /// also gives precise errors when that fails. The command runs `program` as pid 1 in the
+    /// sandbox, so unlike with [`BubLauncher::command`] there's no `--` to add.
+    ///
+    /// Spawn it through [`SandboxHandle`](crate::SandboxHandle) to find out about the sandbox.
+    pub fn native_command<S: AsRef<OsStr>>(
+        mut self,
+        program: S,
+    ) -> Result<(Command, SandboxStatus)> {
+        if self.userns.is_some() {
+            return Err(SandboxError::NativeUnsupported("joining a user namespace"));
+        }
+        if self.assert_userns_disabled && !self.disable_userns {
+            return Err(SandboxError::NativeUnsupported(
+                "asserting userns are disabled",
+            ));
+        }
+        if self.scope.is_some() {
+            return Err(SandboxError::NativeUnsupported("systemd scopes"));
+        }
+        if !self.cap_add.is_empty() || self.cap_drop.iter().any(|x| x != "ALL") {
+            return Err(SandboxError::NativeUnsupported(
+                "capabilities other than ALL",
+            ));
+        }
+
+        let options = NativeOptions {
+            mounts: self.take_mounts(),
+            unshare_net: self.unshare_net,
+            unshare_ipc: self.unshare_ipc,
+            unshare_uts: self.unshare_uts,
+            unshare_cgroups: self.unshare_cgroups,
+            hostname: self.hostname,
+            uid: self.uid,
+            gid: self.gid,
+            new_session: self.new_session,
+            die_with_parent: self.die_with_parent,
+            disable_userns: self.disable_userns,
+            drop_caps: !self.cap_drop.is_empty(),
+            seccomp: self.seccomp.map(|x| x.compile()).transpose()?,
+        };
+        let (mut cmd, status) = native::command(program.as_ref(), options)?;
+        set_rlimits(&mut cmd, std::mem::take(&mut self.rlimits));
+
+        if !self.inherit_env {
+            cmd.env_clear();
+        }
+
+        let inherit_env = self.inherit_env;
+        let inherited = |key: &str| match inherit_env {
+            true => std::env::var(key).ok(),
+            false => None,
+        };
+
+        for (key, value) in resolve_env(self.env, inherited)? {
+            match value {
+                Some(value) => cmd.env(key, value),
+                None => cmd.env_remove(key),
+            };
+        }
+
+        Ok((cmd, status))
+    }
+
+    /// Describes the same sandbox as an OCI bundle in the directory at `path`, which runs `args`.
+    /// Nothing is written until [`OciBundle::write`] is called.
+    pub fn oci_bundle<P, I, S>(mut self, path: P, args: I) -> Result<OciBundle>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        // The runtime may run from anywhere, so the paths into the bundle have to be absolute
+        let path = std::env::current_dir()?.join(path);
+
+        // The config gets written to disk and shared, so the rest of the environment of the host
+        // is left out even if it would be inherited
+        let inherit_env = self.inherit_env;
+        let inherited = |key: &str| match inherit_env {
+            true => std::env::var(key).ok(),
+            false => None,
+        };
+
+        let env = resolve_env(std::mem::take(&mut self.env), inherited)?
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect();
+
+        let options = OciOptions {
+            mounts: self.take_mounts(),
+            args: args.into_iter().map(Into::into).collect(),
+            env,
+            inherit_env: self.inherit_env,
+            unshare_user: self.unshare_user || self.unshare_user_try,
+            unshare_ipc: self.unshare_ipc,
+            unshare_pid: self.unshare_pid,
+            unshare_net: self.unshare_net,
+            unshare_uts: self.unshare_uts,
+            unshare_cgroups: self.unshare_cgroups,
+            userns: self.userns,
+            hostname: self.hostname,
+            uid: self.uid,
+            gid: self.gid,
+            disable_userns: self.disable_userns,
+            cap_add: self.cap_add,
+            rlimits: self.rlimits,
+            seccomp: self.seccomp,
+        };
+
+        Ok(oci::bundle(path, options))
+    }
+
+    /// The mounts, followed by the files for the user and machine id. These go after the other
+    /// mounts, so a bind of the whole of /etc doesn't hide them.
+    fn take_mounts(&mut self) -> Vec<BubMount> {
+        let mut mounts = std::mem::take(&mut self.mounts);
+
+        if let Some(user) = &self.user {
+            mounts.push(BubMount::bind_data_ro(user.passwd(), "/etc/passwd"));
+            mounts.push(BubMount::bind_data_ro(user.group(), "/etc/group"));
+        }
+        if let Some(id) = &self.machine_id {
+            mounts.push(BubMount::bind_data_ro(
+                format!("{}\n", id),
+                "/etc/machine-id",
+            ));
+        }
+
+        mounts
+    }
+
+    /// Fails if an option was requested which the bwrap we're using doesn't have, for options
+    /// which can't be left out without making the sandbox weaker
+    fn check_features(&self) -> Result<()> {
+        for feature in self.mounts.iter().flat_map(BubMount::features) {
+            self.require(feature)?;
+        }
+        // The user and machine id get written into files with --bind-data
+        if self.user.is_some() || self.machine_id.is_some() {
+            self.require(BwrapFeature::BindData)?;
+        }
+        if self.disable_userns || self.assert_userns_disabled {
+            self.require(BwrapFeature::DisableUserns)?;
+        }
+
+        Ok(())
+    }
+
+    /// Without a detected bwrap, everything is assumed to be supported
+    fn supports(&self, feature: BwrapFeature) -> bool {
+        self.bwrap.as_ref().is_none_or(|x| x.supports(feature))
+    }
+
+    fn require(&self, feature: BwrapFeature) -> Result<()> {
+        self.bwrap.as_ref().map_or(Ok(()), |x| x.require(feature))
+    }
+
+    /// Uses a bwrap found with [`Bwrap::locate`], rather than assuming it is at `/usr/bin/bwrap`
+    /// and supports every option
+    pub fn executable(&mut self, bwrap: Bwrap) -> &mut BubLauncher {
+        self.bwrap = Some(bwrap);
+        self
+    }
+
+    /// Marks a path which has to be visible inside of the sandbox, see [`BubLauncher::validate`]
+    pub fn require_path<T: Into<PathBuf>>(&mut self, path: T) -> &mut BubLauncher {
+        self.required.push(path.into());
+        self
+    }
+
+    /// Looks for problems with the mounts which would otherwise only show up once bwrap runs, or
+    /// not at all
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        validate::validate(&self.mounts, &self.required)
+    }
+
+    /// Grants what the mounts put in place, so Landlock can be stacked on top of the sandbox
+    pub fn landlock_ruleset(&self) -> LandlockRuleset {
+        LandlockRuleset::from_mounts(&self.mounts)
+    }
+
+    /// The mounts added so far, in the order bwrap sets them up
+    pub fn mounts(&self) -> &[BubMount] {
+        &self.mounts
     }
 
     pub fn mount(&mut self, mount: BubMount) -> &mut BubLauncher {
@@ -161,7 +820,8 @@ impl BubLauncher {
 
     /// # Order
     /// Environmental modifications are applied in the order that they're added onto the struct.
-    /// This means that if you add the env var `derp` then call `env_unset` it will be removed.
+    /// This means that if you add the env var `derp` and then an [`EnvVar::Unset`] for it, it will
+    /// be removed.
     pub fn env<T: Into<EnvVar>>(&mut self, var: T) -> &mut BubLauncher {
         self.env.push(var.into());
         self
@@ -174,15 +834,138 @@ impl BubLauncher {
         self
     }
 
+    /// Sets the hostname, which requires its own UTS namespace
     pub fn hostname<T: Into<String>>(&mut self, hostname: T) -> &mut BubLauncher {
+        self.unshare_uts = true;
         self.hostname = Some(hostname.into());
         self
     }
 
-    pub fn no_hostname<T: Into<String>>(&mut self) -> &mut BubLauncher {
+    pub fn no_hostname(&mut self) -> &mut BubLauncher {
         self.hostname = None;
         self
     }
+
+    /// Sets the uid inside of the sandbox, which requires its own user namespace
+    pub fn uid(&mut self, uid: u32) -> &mut BubLauncher {
+        self.unshare_user = true;
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Sets the gid inside of the sandbox, which requires its own user namespace
+    pub fn gid(&mut self, gid: u32) -> &mut BubLauncher {
+        self.unshare_user = true;
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Runs the program as `user`, with a `/etc/passwd` and `/etc/group` which only know about
+    /// that user instead of the ones of the host
+    pub fn user(&mut self, user: SandboxUser) -> &mut BubLauncher {
+        self.uid(user.uid).gid(user.gid);
+        self.user = Some(user);
+        self
+    }
+
+    /// The files written for [`BubLauncher::user`] and [`BubLauncher::machine_id`], which go on
+    /// top of every other mount
+    pub fn generated_files(&self) -> Vec<&Path> {
+        let mut files = Vec::new();
+        if self.user.is_some() {
+            files.extend([Path::new("/etc/passwd"), Path::new("/etc/group")]);
+        }
+        if self.machine_id.is_some() {
+            files.push(Path::new("/etc/machine-id"));
+        }
+        files
+    }
+
+    /// Gives the sandbox its own `/etc/machine-id`, so programs can't tell which host they run on
+    pub fn machine_id<T: Into<String>>(&mut self, id: T) -> &mut BubLauncher {
+        self.machine_id = Some(id.into());
+        self
+    }
+
+    pub fn unshare_user(&mut self, unshare: bool) -> &mut BubLauncher {
+        self.unshare_user = unshare;
+        self
+    }
+
+    /// Creates a new user namespace if possible, but carries on without one if it is not
+    pub fn unshare_user_try(&mut self, unshare: bool) -> &mut BubLauncher {
+        self.unshare_user_try = unshare;
+        self
+    }
+
+    /// Prevents the sandbox from creating any further user namespaces, requires `unshare_user`
+    pub fn disable_userns(&mut self, disable: bool) -> &mut BubLauncher {
+        self.disable_userns = disable;
+        self
+    }
+
+    /// Fails to start unless nested user namespaces are already disabled, for when the sandbox
+    /// joins an existing namespace with [`BubLauncher::userns`]
+    pub fn assert_userns_disabled(&mut self, assert: bool) -> &mut BubLauncher {
+        self.assert_userns_disabled = assert;
+        self
+    }
+
+    /// Uses an existing user namespace, such as `/proc/<pid>/ns/user`, instead of creating one
+    pub fn userns<T: Into<PathBuf>>(&mut self, ns: T) -> &mut BubLauncher {
+        self.userns = Some(ns.into());
+        self
+    }
+
+    /// Drops a capability like `CAP_SYS_ADMIN`, or all of them with `ALL`
+    pub fn cap_drop<T: Into<String>>(&mut self, cap: T) -> &mut BubLauncher {
+        self.cap_drop.push(cap.into());
+        self
+    }
+
+    /// Keeps a capability like `CAP_NET_RAW`, or all of them with `ALL`
+    pub fn cap_add<T: Into<String>>(&mut self, cap: T) -> &mut BubLauncher {
+        self.cap_add.push(cap.into());
+        self
+    }
+
+    pub fn unshare_net(&mut self, unshare: bool) -> &mut BubLauncher {
+        self.unshare_net = unshare;
+        self
+    }
+
+    /// Locks the sandbox down as far as wine allows: no capabilities are kept and the sandboxed
+    /// program can't create user namespaces to get them back.
+    pub fn harden(&mut self) -> &mut BubLauncher {
+        self.unshare_user(true).disable_userns(true).cap_drop("ALL")
+    }
+
+    /// Hands the arguments to bwrap through `--args` rather than its argv, which keeps the values
+    /// of env vars out of the process list of the host
+    pub fn args_fd(&mut self, enabled: bool) -> &mut BubLauncher {
+        self.args_fd = enabled;
+        self
+    }
+
+    /// Filters the syscalls of the sandboxed program, see [`SeccompPolicy`]
+    pub fn seccomp(&mut self, policy: SeccompPolicy) -> &mut BubLauncher {
+        self.seccomp = Some(policy);
+        self
+    }
+
+    /// Starts bwrap in the scope, so everything in the sandbox can be accounted for, limited and
+    /// stopped together
+    pub fn systemd_scope(&mut self, scope: SystemdScope) -> &mut BubLauncher {
+        self.scope = Some(scope);
+        self
+    }
+
+    /// Sets the limit, replacing an earlier limit on the same resource
+    pub fn rlimit(&mut self, limit: Rlimit) -> &mut BubLauncher {
+        self.rlimits.retain(|x| !x.same_resource(&limit));
+        self.rlimits.push(limit);
+        self
+    }
 }
 
 impl Default for BubLauncher {
@@ -206,6 +989,24 @@ impl Default for BubLauncher {
             hostname: None,
             uid: None,
             gid: None,
+            user: None,
+            machine_id: None,
+
+            seccomp: None,
+            rlimits: Vec::new(),
+            scope: None,
+
+            unshare_user_try: false,
+            disable_userns: false,
+            assert_userns_disabled: false,
+            userns: None,
+            cap_drop: Vec::new(),
+            cap_add: Vec::new(),
+
+            args_fd: false,
+
+            bwrap: None,
+            required: Vec::new(),
         }
     }
 }
@@ -0,0 +1,157 @@
+use crate::{Result, SandboxError};
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub const BWRAP_BIN_NAME: &str = "bwrap";
+pub const BWRAP_DEFAULT_PATH: &str = "/usr/bin/bwrap";
+
+/// Options which not every version of bubblewrap understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BwrapFeature {
+    Args,
+    BindData,
+    Chmod,
+    DisableUserns,
+    InfoFd,
+    JsonStatusFd,
+    Overlay,
+    Perms,
+    RemountRo,
+    Size,
+}
+
+impl BwrapFeature {
+    pub const ALL: [BwrapFeature; 10] = [
+        Self::Args,
+        Self::BindData,
+        Self::Chmod,
+        Self::DisableUserns,
+        Self::InfoFd,
+        Self::JsonStatusFd,
+        Self::Overlay,
+        Self::Perms,
+        Self::RemountRo,
+        Self::Size,
+    ];
+
+    /// The flag which shows up in `bwrap --help` when the feature is there
+    pub const fn flag(&self) -> &'static str {
+        match self {
+            Self::Args => "--args",
+            Self::BindData => "--bind-data",
+            Self::Chmod => "--chmod",
+            Self::DisableUserns => "--disable-userns",
+            Self::InfoFd => "--info-fd",
+            Self::JsonStatusFd => "--json-status-fd",
+            Self::Overlay => "--overlay",
+            Self::Perms => "--perms",
+            Self::RemountRo => "--remount-ro",
+            Self::Size => "--size",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BwrapVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl std::fmt::Display for BwrapVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl BwrapVersion {
+    /// Parses the output of `bwrap --version`, which looks like `bubblewrap 0.8.0`
+    pub fn parse(output: &str) -> Option<BwrapVersion> {
+        let mut parts = output
+            .trim()
+            .strip_prefix("bubblewrap ")?
+            .split('.')
+            .map(|x| x.parse::<u32>());
+
+        Some(BwrapVersion {
+            major: parts.next()?.ok()?,
+            minor: parts.next()?.ok()?,
+            patch: parts.next().unwrap_or(Ok(0)).ok()?,
+        })
+    }
+}
+
+/// A bwrap binary, along with what it is able to do
+#[derive(Debug, Clone)]
+pub struct Bwrap {
+    pub path: PathBuf,
+    pub version: BwrapVersion,
+    features: Vec<BwrapFeature>,
+}
+
+impl Bwrap {
+    /// Finds bwrap at `configured` if given, otherwise in `$PATH`, and probes it for its features
+    pub fn locate<T: AsRef<Path>>(configured: Option<T>) -> Result<Bwrap> {
+        let path = match configured {
+            Some(path) => path.as_ref().to_path_buf(),
+            None => find_in_path(BWRAP_BIN_NAME).unwrap_or_else(|| BWRAP_DEFAULT_PATH.into()),
+        };
+
+        Bwrap::probe(path)
+    }
+
+    pub fn probe<T: Into<PathBuf>>(path: T) -> Result<Bwrap> {
+        let path = path.into();
+
+        let output = Command::new(&path)
+            .arg("--version")
+            .output()
+            .map_err(|_| SandboxError::BwrapMissing(path.clone()))?;
+        let output = String::from_utf8_lossy(&output.stdout);
+        let version = BwrapVersion::parse(&output)
+            .ok_or_else(|| SandboxError::BwrapVersion(output.trim().to_string()))?;
+
+        // Every option is listed in the help, which saves us from keeping a table of versions
+        let help = Command::new(&path).arg("--help").output()?;
+        let help = String::from_utf8_lossy(&help.stdout);
+        let features = BwrapFeature::ALL
+            .iter()
+            .filter(|x| help.split_whitespace().any(|word| word == x.flag()))
+            .copied()
+            .collect();
+
+        Ok(Bwrap {
+            path,
+            version,
+            features,
+        })
+    }
+
+    pub fn supports(&self, feature: BwrapFeature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    /// Fails with [`SandboxError::Unsupported`] if the feature is missing
+    pub fn require(&self, feature: BwrapFeature) -> Result<()> {
+        match self.supports(feature) {
+            true => Ok(()),
+            false => Err(SandboxError::Unsupported {
+                feature,
+                version: self.version,
+            }),
+        }
+    }
+}
+
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let paths = std::env::var_os("PATH")?;
+
+    std::env::split_paths(&paths)
+        .map(|x| x.join(name))
+        .find(|x| match x.metadata() {
+            Ok(meta) => meta.is_file() && meta.permissions().mode() & 0o111 != 0,
+            Err(_) => false,
+        })
+}
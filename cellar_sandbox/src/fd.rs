@@ -0,0 +1,66 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// An in-memory file which is handed to the sandbox through its file descriptor.
+///
+/// The descriptor is close-on-exec in this process, it only gets inherited by the command it is
+/// passed to with [`pass_fds`], after turning it into a [`File`].
+#[derive(Debug)]
+pub struct DataFd(File);
+
+impl DataFd {
+    pub fn new<T: AsRef<[u8]>>(name: &str, data: T) -> io::Result<DataFd> {
+        let name =
+            CString::new(name).map_err(|x| io::Error::new(io::ErrorKind::InvalidInput, x))?;
+
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(data.as_ref())?;
+
+        Ok(DataFd(file))
+    }
+
+    pub fn raw(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl From<DataFd> for File {
+    fn from(fd: DataFd) -> File {
+        fd.0
+    }
+}
+
+/// Makes the descriptors available to the spawned process, at the same numbers they have here.
+///
+/// The command takes ownership of them, so they stay open for as long as the command is around
+/// and are rewound before every spawn so the data can be read again.
+pub fn pass_fds(cmd: &mut Command, fds: Vec<File>) {
+    if fds.is_empty() {
+        return;
+    }
+
+    unsafe {
+        cmd.pre_exec(move || {
+            for fd in fds.iter().map(AsRawFd::as_raw_fd) {
+                // Descriptors which can't seek, like namespaces, are fine to leave as they are
+                libc::lseek(fd, 0, libc::SEEK_SET);
+
+                let flags = libc::fcntl(fd, libc::F_GETFD);
+                if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            Ok(())
+        });
+    }
+}
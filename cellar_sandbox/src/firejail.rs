@@ -1,11 +1,33 @@
+use crate::{resolve_env, EnvVar, Result, SandboxError};
+
+use std::net::IpAddr;
 use std::path::PathBuf;
 use std::process::Command;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+pub const FIREJAIL_DEFAULT_PATH: &str = "/usr/bin/firejail";
 
 #[derive(Debug)]
 pub struct FirejailLauncher {
     firejail_exec: PathBuf,
     whitelists: Vec<PathBuf>,
     blacklists: Vec<PathBuf>,
+    read_only: Vec<PathBuf>,
+    read_write: Vec<PathBuf>,
+    tmpfs: Vec<PathBuf>,
+    noexec: Vec<PathBuf>,
+
+    env: Vec<EnvVar>,
+    rlimits: Vec<Rlimit>,
+    dns: Vec<IpAddr>,
+
+    /// `none`, or the interface the sandbox gets its own network namespace on
+    net: Option<String>,
+    /// The files of `/etc` which are kept, everything else is hidden
+    private_etc: Option<Vec<String>>,
+    name: Option<String>,
 
     pub profile: Option<PathBuf>,
     pub private_tmp: bool,
@@ -28,22 +50,55 @@ pub struct FirejailLauncher {
 
 #[allow(dead_code)]
 impl FirejailLauncher {
-    pub fn command(self) -> Command {
+    pub fn command(self) -> Result<Command> {
         let mut cmd = Command::new(self.firejail_exec);
 
-        self.whitelists
-            .into_iter()
-            .map(|x| format!("--whitelist={}", x.display()))
-            .for_each(|x| {
-                cmd.arg(x);
-            });
+        macro_rules! apply_paths {
+            ($property: ident, $flag: expr) => {
+                self.$property
+                    .into_iter()
+                    .map(|x| format!("{}={}", $flag, x.display()))
+                    .for_each(|x| {
+                        cmd.arg(x);
+                    });
+            };
+        }
+
+        apply_paths!(whitelists, "--whitelist");
+        apply_paths!(blacklists, "--blacklist");
+        apply_paths!(read_only, "--read-only");
+        apply_paths!(read_write, "--read-write");
+        apply_paths!(tmpfs, "--tmpfs");
+        apply_paths!(noexec, "--noexec");
+
+        // firejail starts out with our env, so that's what appending and prepending builds on
+        for (key, value) in resolve_env(self.env, |x| std::env::var(x).ok())? {
+            match value {
+                Some(value) => cmd.arg(format!("--env={}={}", key, value)),
+                None => cmd.arg(format!("--rmenv={}", key)),
+            };
+        }
+
+        self.rlimits.iter().for_each(|x| {
+            cmd.arg(x.arg());
+        });
 
-        self.blacklists
-            .into_iter()
-            .map(|x| format!("--blacklist={}", x.display()))
-            .for_each(|x| {
-                cmd.arg(x);
-            });
+        if let Some(net) = self.net {
+            cmd.arg(format!("--net={}", net));
+        }
+        self.dns.iter().for_each(|x| {
+            cmd.arg(format!("--dns={}", x));
+        });
+
+        match self.private_etc {
+            Some(files) if files.is_empty() => cmd.arg("--private-etc"),
+            Some(files) => cmd.arg(format!("--private-etc={}", files.join(","))),
+            None => &mut cmd,
+        };
+
+        if let Some(name) = self.name {
+            cmd.arg(format!("--name={}", name));
+        }
 
         if let Some(profile) = self.profile {
             let profile_path = format!("--profile={}", profile.display());
@@ -83,7 +138,7 @@ impl FirejailLauncher {
 
         apply_bool!(seccomp);
 
-        cmd
+        Ok(cmd)
     }
 
     pub fn whitelist(&mut self, path: PathBuf) -> &mut FirejailLauncher {
@@ -106,18 +161,113 @@ impl FirejailLauncher {
         self
     }
 
+    pub fn x11(&mut self, sandbox: X11Sandbox) -> &mut FirejailLauncher {
+        self.x11 = Some(sandbox);
+        self
+    }
+
     pub fn private_tmp(&mut self, private: bool) -> &mut FirejailLauncher {
         self.private_tmp = private;
         self
     }
+
+    pub fn private_cache(&mut self, private: bool) -> &mut FirejailLauncher {
+        self.private_cache = private;
+        self
+    }
+
+    pub fn seccomp(&mut self, enabled: bool) -> &mut FirejailLauncher {
+        self.seccomp = enabled;
+        self
+    }
+
+    pub fn read_only(&mut self, path: PathBuf) -> &mut FirejailLauncher {
+        self.read_only.push(path);
+        self
+    }
+
+    /// Makes a path below a [`FirejailLauncher::read_only`] one writable again
+    pub fn read_write(&mut self, path: PathBuf) -> &mut FirejailLauncher {
+        self.read_write.push(path);
+        self
+    }
+
+    pub fn tmpfs(&mut self, path: PathBuf) -> &mut FirejailLauncher {
+        self.tmpfs.push(path);
+        self
+    }
+
+    pub fn noexec(&mut self, path: PathBuf) -> &mut FirejailLauncher {
+        self.noexec.push(path);
+        self
+    }
+
+    /// Env vars are applied in the order they're added, like with
+    /// [`BubLauncher::env`](crate::BubLauncher::env)
+    pub fn env<T: Into<EnvVar>>(&mut self, var: T) -> &mut FirejailLauncher {
+        self.env.push(var.into());
+        self
+    }
+
+    /// Sets the limit, replacing an earlier limit on the same resource
+    pub fn rlimit(&mut self, limit: Rlimit) -> &mut FirejailLauncher {
+        self.rlimits.retain(|x| !x.same_resource(&limit));
+        self.rlimits.push(limit);
+        self
+    }
+
+    /// Gives the sandbox a network namespace without any interfaces
+    pub fn net_none(&mut self) -> &mut FirejailLauncher {
+        self.net = Some("none".to_string());
+        self
+    }
+
+    /// Gives the sandbox its own network namespace, connected to the host through `interface`
+    pub fn net<T: Into<String>>(&mut self, interface: T) -> &mut FirejailLauncher {
+        self.net = Some(interface.into());
+        self
+    }
+
+    pub fn dns(&mut self, server: IpAddr) -> &mut FirejailLauncher {
+        self.dns.push(server);
+        self
+    }
+
+    /// Hides everything in `/etc` but `files`, which can be empty to hide all of it
+    pub fn private_etc<I, T>(&mut self, files: I) -> &mut FirejailLauncher
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.private_etc = Some(files.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// The name of the sandbox, which other firejail commands can refer to it by
+    pub fn name<T: Into<String>>(&mut self, name: T) -> &mut FirejailLauncher {
+        self.name = Some(name.into());
+        self
+    }
 }
 
 impl Default for FirejailLauncher {
     fn default() -> FirejailLauncher {
         FirejailLauncher {
-            firejail_exec: PathBuf::from("/usr/bin/firejail"),
+            firejail_exec: PathBuf::from(FIREJAIL_DEFAULT_PATH),
             whitelists: Vec::new(),
             blacklists: Vec::new(),
+            read_only: Vec::new(),
+            read_write: Vec::new(),
+            tmpfs: Vec::new(),
+            noexec: Vec::new(),
+
+            env: Vec::new(),
+            rlimits: Vec::new(),
+            dns: Vec::new(),
+
+            net: None,
+            private_etc: None,
+            name: None,
 
             profile: None,
             private_tmp: true,
@@ -140,9 +290,160 @@ impl Default for FirejailLauncher {
     }
 }
 
-// TODO Add ability to change sandbox
+/// A running sandbox, as listed by `firejail --list`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirejailSandbox {
+    pub pid: i32,
+    pub user: String,
+    pub name: Option<String>,
+    pub command: String,
+}
+
+impl FirejailSandbox {
+    /// Parses a line of `firejail --list`, which looks like `1234:user:name:firejail --name=name
+    /// program`, with the name left empty for sandboxes without one
+    pub fn parse(line: &str) -> Option<FirejailSandbox> {
+        let mut parts = line.trim().splitn(4, ':');
+
+        let pid = parts.next()?.parse().ok()?;
+        let user = parts.next()?.to_string();
+        let name = parts.next()?;
+        let command = parts.next()?.to_string();
+
+        Some(FirejailSandbox {
+            pid,
+            user,
+            name: (!name.is_empty()).then(|| name.to_string()),
+            command,
+        })
+    }
+}
+
+/// Lists the sandboxes of every user which are running right now
+pub fn list() -> Result<Vec<FirejailSandbox>> {
+    let output = Command::new(FIREJAIL_DEFAULT_PATH).arg("--list").output()?;
+    if !output.status.success() {
+        return Err(SandboxError::FirejailFailed(output.status));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(FirejailSandbox::parse)
+        .collect())
+}
+
+/// A command which runs the program given to it inside of the named sandbox, or a shell without
+/// one
+pub fn join(name: &str) -> Command {
+    let mut cmd = Command::new(FIREJAIL_DEFAULT_PATH);
+    cmd.arg(format!("--join={}", name));
+    cmd
+}
+
+/// Stops the named sandbox along with everything running inside of it
+pub fn shutdown(name: &str) -> Result<()> {
+    let status = Command::new(FIREJAIL_DEFAULT_PATH)
+        .arg(format!("--shutdown={}", name))
+        .status()?;
+
+    match status.success() {
+        true => Ok(()),
+        false => Err(SandboxError::FirejailFailed(status)),
+    }
+}
+
+/// A resource limit of the sandbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rlimit {
+    /// Bytes of virtual memory
+    As(u64),
+    /// Seconds of cpu time
+    Cpu(u64),
+    /// Bytes of the largest file which can be written
+    Fsize(u64),
+    Nofile(u64),
+    Nproc(u64),
+    Sigpending(u64),
+}
+
+impl Rlimit {
+    pub fn arg(&self) -> String {
+        match self {
+            Self::As(x) => format!("--rlimit-as={}", x),
+            Self::Cpu(x) => format!("--rlimit-cpu={}", x),
+            Self::Fsize(x) => format!("--rlimit-fsize={}", x),
+            Self::Nofile(x) => format!("--rlimit-nofile={}", x),
+            Self::Nproc(x) => format!("--rlimit-nproc={}", x),
+            Self::Sigpending(x) => format!("--rlimit-sigpending={}", x),
+        }
+    }
+
+    /// The name of the resource, as `setrlimit` and the OCI spec call it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::As(_) => "RLIMIT_AS",
+            Self::Cpu(_) => "RLIMIT_CPU",
+            Self::Fsize(_) => "RLIMIT_FSIZE",
+            Self::Nofile(_) => "RLIMIT_NOFILE",
+            Self::Nproc(_) => "RLIMIT_NPROC",
+            Self::Sigpending(_) => "RLIMIT_SIGPENDING",
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        match *self {
+            Self::As(x)
+            | Self::Cpu(x)
+            | Self::Fsize(x)
+            | Self::Nofile(x)
+            | Self::Nproc(x)
+            | Self::Sigpending(x) => x,
+        }
+    }
+
+    /// Whether both limit the same resource
+    pub fn same_resource(&self, other: &Rlimit) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    pub(crate) fn resource(&self) -> libc::c_int {
+        (match self {
+            Self::As(_) => libc::RLIMIT_AS,
+            Self::Cpu(_) => libc::RLIMIT_CPU,
+            Self::Fsize(_) => libc::RLIMIT_FSIZE,
+            Self::Nofile(_) => libc::RLIMIT_NOFILE,
+            Self::Nproc(_) => libc::RLIMIT_NPROC,
+            Self::Sigpending(_) => libc::RLIMIT_SIGPENDING,
+        }) as libc::c_int
+    }
+}
+
+/// Reads a limit like `nofile=4096`
+impl FromStr for Rlimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (resource, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected a limit like \"nofile=4096\", got \"{}\"", s))?;
+        let value = value
+            .parse::<u64>()
+            .map_err(|_| format!("Invalid limit \"{}\"", value))?;
+
+        match resource.to_ascii_lowercase().as_ref() {
+            "as" => Ok(Self::As(value)),
+            "cpu" => Ok(Self::Cpu(value)),
+            "fsize" => Ok(Self::Fsize(value)),
+            "nofile" => Ok(Self::Nofile(value)),
+            "nproc" => Ok(Self::Nproc(value)),
+            "sigpending" => Ok(Self::Sigpending(value)),
+            _ => Err(format!("Unknown resource \"{}\"", resource)),
+        }
+    }
+}
+
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum X11Sandbox {
     DEFAULT,
     XEPHYR,
@@ -157,8 +458,8 @@ impl X11Sandbox {
             Self::DEFAULT => "--x11",
             Self::XEPHYR => "--x11=xephyr",
             Self::XORG => "--x11=xorg",
-            Self::XPRA => "--x11-xpra",
-            Self::XVFB => "--x11-xvfb",
+            Self::XPRA => "--x11=xpra",
+            Self::XVFB => "--x11=xvfb",
         }
     }
 }
@@ -0,0 +1,603 @@
+use crate::{BubLauncher, BubMount, Result, SeccompPolicy};
+
+use std::collections::HashSet;
+use std::fmt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+pub const FIREJAIL_CONFIG_DIR: &str = "/etc/firejail";
+
+/// The options of a firejail profile which cellar knows about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileEntry {
+    Whitelist(PathBuf),
+    Blacklist(PathBuf),
+    NoBlacklist(PathBuf),
+    ReadOnly(PathBuf),
+    ReadWrite(PathBuf),
+    Tmpfs(PathBuf),
+    Noexec(PathBuf),
+
+    /// An empty home directory
+    Private,
+    PrivateCache,
+    PrivateDev,
+    PrivateEtc(Vec<String>),
+    PrivateTmp,
+
+    /// `none`, or an interface
+    Net(String),
+    Seccomp,
+    CapsDropAll,
+
+    /// One of the `no*` options, which only turn something off
+    Flag(String),
+}
+
+impl fmt::Display for ProfileEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Whitelist(x) => write!(f, "whitelist {}", x.display()),
+            Self::Blacklist(x) => write!(f, "blacklist {}", x.display()),
+            Self::NoBlacklist(x) => write!(f, "noblacklist {}", x.display()),
+            Self::ReadOnly(x) => write!(f, "read-only {}", x.display()),
+            Self::ReadWrite(x) => write!(f, "read-write {}", x.display()),
+            Self::Tmpfs(x) => write!(f, "tmpfs {}", x.display()),
+            Self::Noexec(x) => write!(f, "noexec {}", x.display()),
+            Self::Private => write!(f, "private"),
+            Self::PrivateCache => write!(f, "private-cache"),
+            Self::PrivateDev => write!(f, "private-dev"),
+            Self::PrivateEtc(x) => write!(f, "private-etc {}", x.join(",")),
+            Self::PrivateTmp => write!(f, "private-tmp"),
+            Self::Net(x) => write!(f, "net {}", x),
+            Self::Seccomp => write!(f, "seccomp"),
+            Self::CapsDropAll => write!(f, "caps.drop all"),
+            Self::Flag(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// A line of a profile which couldn't be used, along with why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Untranslated {
+    pub line: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for Untranslated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\": {}", self.line, self.reason)
+    }
+}
+
+const FLAGS: [&str; 9] = [
+    "no3d",
+    "nodbus",
+    "nodvd",
+    "nogroups",
+    "nonewprivs",
+    "noroot",
+    "nosound",
+    "novideo",
+    "nou2f",
+];
+
+/// A firejail `.profile`, with its includes already followed
+#[derive(Debug, Clone, Default)]
+pub struct FirejailProfile {
+    pub entries: Vec<ProfileEntry>,
+    /// Lines which weren't understood while reading the profile
+    pub unsupported: Vec<Untranslated>,
+}
+
+impl FirejailProfile {
+    pub fn load<T: AsRef<Path>>(path: T) -> Result<FirejailProfile> {
+        let mut profile = FirejailProfile::default();
+        profile.read(path.as_ref(), &mut HashSet::new())?;
+        Ok(profile)
+    }
+
+    fn read(&mut self, path: &Path, seen: &mut HashSet<PathBuf>) -> Result<()> {
+        // Profiles commonly include each other through `globals.local` and the like
+        if !seen.insert(path.canonicalize()?) {
+            return Ok(());
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (option, value) = match line.split_once(char::is_whitespace) {
+                Some((option, value)) => (option, value.trim()),
+                None => (line, ""),
+            };
+
+            if option == "include" {
+                match find_include(&expand(value), dir) {
+                    Some(include) => self.read(&include, seen)?,
+                    // firejail skips missing `.local` files too, they're only there for overrides
+                    None if value.ends_with(".local") => {}
+                    None => self.unsupported(line, "the included profile doesn't exist"),
+                }
+                continue;
+            }
+
+            let path = || expand(value);
+            let entry = match option {
+                "whitelist" => ProfileEntry::Whitelist(path()),
+                "blacklist" => ProfileEntry::Blacklist(path()),
+                "noblacklist" => ProfileEntry::NoBlacklist(path()),
+                "read-only" => ProfileEntry::ReadOnly(path()),
+                "read-write" => ProfileEntry::ReadWrite(path()),
+                "tmpfs" => ProfileEntry::Tmpfs(path()),
+                "noexec" => ProfileEntry::Noexec(path()),
+                "private" if value.is_empty() => ProfileEntry::Private,
+                "private-cache" => ProfileEntry::PrivateCache,
+                "private-dev" => ProfileEntry::PrivateDev,
+                "private-etc" => ProfileEntry::PrivateEtc(
+                    value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|x| !x.is_empty())
+                        .map(String::from)
+                        .collect(),
+                ),
+                "private-tmp" => ProfileEntry::PrivateTmp,
+                "net" if !value.is_empty() => ProfileEntry::Net(value.to_string()),
+                "seccomp" if value.is_empty() => ProfileEntry::Seccomp,
+                "caps.drop" if value == "all" => ProfileEntry::CapsDropAll,
+                flag if FLAGS.contains(&flag) => ProfileEntry::Flag(flag.to_string()),
+                _ => {
+                    self.unsupported(line, "cellar doesn't know about this option");
+                    continue;
+                }
+            };
+
+            self.entries.push(entry);
+        }
+
+        Ok(())
+    }
+
+    fn unsupported(&mut self, line: &str, reason: &'static str) {
+        self.unsupported.push(Untranslated {
+            line: line.to_string(),
+            reason,
+        });
+    }
+
+    /// Blacklisted paths without the ones which were taken off the blacklist again
+    fn blacklist(&self) -> Vec<&PathBuf> {
+        let allowed = self
+            .entries
+            .iter()
+            .filter_map(|x| match x {
+                ProfileEntry::NoBlacklist(path) => Some(path),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        self.entries
+            .iter()
+            .filter_map(|x| match x {
+                ProfileEntry::Blacklist(path) if !allowed.contains(&path) => Some(path),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Paths which were made writable with `read-write`
+    fn read_write(&self) -> impl Iterator<Item = &PathBuf> {
+        self.entries.iter().filter_map(|x| match x {
+            ProfileEntry::ReadWrite(path) => Some(path),
+            _ => None,
+        })
+    }
+
+    /// Sets up the launcher to match the profile as closely as bubblewrap allows, giving back the
+    /// entries which it couldn't match.
+    ///
+    /// Paths in the home of the user end up in `home` in the sandbox, which is what `${HOME}`
+    /// points to in there.
+    pub fn apply_bubblewrap(&self, launcher: &mut BubLauncher, home: &Path) -> Vec<Untranslated> {
+        let mut untranslated = Vec::new();
+        let mut skip = |entry: &ProfileEntry, reason| {
+            untranslated.push(Untranslated {
+                line: entry.to_string(),
+                reason,
+            })
+        };
+
+        let host_home = std::env::var_os("HOME").map(PathBuf::from);
+        let sandboxed = |path: &Path| match host_home.as_ref().map(|x| path.strip_prefix(x)) {
+            Some(Ok(rest)) => home.join(rest),
+            _ => path.to_path_buf(),
+        };
+
+        for entry in &self.entries {
+            match entry {
+                ProfileEntry::Whitelist(path)
+                | ProfileEntry::ReadOnly(path)
+                | ProfileEntry::ReadWrite(path)
+                | ProfileEntry::Tmpfs(path)
+                | ProfileEntry::Noexec(path)
+                    if has_wildcard(path) =>
+                {
+                    skip(entry, "wildcards only work with firejail")
+                }
+
+                // Nothing is there unless it is mounted, so whitelisting is the same as mounting.
+                // It stays read only unless a read-write entry covers it, so importing a profile
+                // doesn't open up more of the host than it did before.
+                ProfileEntry::Whitelist(path) if path.exists() => {
+                    if self.read_write().any(|x| path.starts_with(x)) {
+                        launcher.mount(BubMount::bind_rw(path, sandboxed(path)));
+                    } else {
+                        launcher.mount(BubMount::bind_ro(path, sandboxed(path)));
+                    }
+                }
+                ProfileEntry::Whitelist(_) => skip(entry, "the path doesn't exist"),
+                ProfileEntry::ReadOnly(path) => {
+                    match read_only(launcher.mounts(), &sandboxed(path)) {
+                        Ok(Some(mount)) => {
+                            launcher.mount(mount);
+                        }
+                        Ok(None) => {}
+                        Err(reason) => skip(entry, reason),
+                    }
+                }
+                // Mounts are writable unless they're made read only, or whitelisted
+                ProfileEntry::ReadWrite(_) => {}
+                ProfileEntry::Tmpfs(path) => {
+                    launcher.mount(BubMount::tmpfs(sandboxed(path)));
+                }
+                ProfileEntry::Noexec(_) => skip(entry, "bubblewrap can't mount with noexec"),
+
+                ProfileEntry::Private => {
+                    launcher.mount(BubMount::tmpfs(home));
+                }
+                ProfileEntry::PrivateCache => {
+                    launcher.mount(BubMount::tmpfs(home.join(".cache")));
+                }
+                ProfileEntry::PrivateEtc(files) => {
+                    launcher.mount(BubMount::tmpfs("/etc"));
+                    files
+                        .iter()
+                        .map(|x| Path::new("/etc").join(x))
+                        .filter(|x| x.exists())
+                        .for_each(|x| {
+                            launcher.mount(BubMount::bind_ro(&x, &x));
+                        });
+                }
+                // The sandbox already has a tmpfs on /tmp, another one on top would hide what has
+                // been put in there and drop its size limit
+                ProfileEntry::PrivateTmp => {}
+                ProfileEntry::PrivateDev => skip(entry, "bubblewrap launchers bind /dev as it is"),
+
+                ProfileEntry::Net(net) if net == "none" => {
+                    launcher.unshare_net(true);
+                }
+                ProfileEntry::Net(_) => skip(entry, "bubblewrap can't set up network interfaces"),
+                ProfileEntry::Seccomp => {
+                    launcher.seccomp(SeccompPolicy::wine());
+                }
+                ProfileEntry::CapsDropAll => {
+                    launcher.cap_drop("ALL");
+                }
+
+                // bubblewrap always sets no_new_privs
+                ProfileEntry::Flag(flag) if flag == "nonewprivs" => {}
+                ProfileEntry::Flag(flag) if flag == "noroot" => {
+                    launcher.unshare_user(true);
+                }
+                ProfileEntry::Flag(_) => skip(entry, "bubblewrap has no matching option"),
+
+                ProfileEntry::Blacklist(_) | ProfileEntry::NoBlacklist(_) => {}
+            }
+        }
+
+        // Parts of whitelisted paths can be made writable on their own
+        for path in self.read_write() {
+            let whitelisted = self.entries.iter().any(|x| match x {
+                ProfileEntry::Whitelist(parent) => path.starts_with(parent) && path != parent,
+                _ => false,
+            });
+
+            if whitelisted && path.exists() && !has_wildcard(path) {
+                launcher.mount(BubMount::bind_rw(path, sandboxed(path)));
+            }
+        }
+
+        // These have to come last, so they hide whatever was mounted on top of them
+        for path in self.blacklist() {
+            let blacklisted = || format!("blacklist {}", path.display());
+
+            // Only the files bwrap writes itself come after this
+            if launcher
+                .generated_files()
+                .iter()
+                .any(|x| x.starts_with(sandboxed(path)))
+            {
+                untranslated.push(Untranslated {
+                    line: blacklisted(),
+                    reason: "bubblewrap writes the user and machine id files after it",
+                });
+            }
+
+            match path.metadata() {
+                _ if has_wildcard(path) => untranslated.push(Untranslated {
+                    line: blacklisted(),
+                    reason: "wildcards only work with firejail",
+                }),
+                Ok(meta) if meta.is_dir() => {
+                    launcher.mount(BubMount::tmpfs(sandboxed(path)));
+                }
+                Ok(_) => {
+                    launcher.mount(BubMount::bind_ro("/dev/null", sandboxed(path)));
+                }
+                // Nothing to hide
+                Err(_) => {}
+            }
+        }
+
+        untranslated
+    }
+}
+
+/// Replaces the macros firejail has for paths in profiles, which don't need to be defined
+fn expand(value: &str) -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_default();
+    let uid = std::fs::metadata("/proc/self")
+        .map(|x| x.uid())
+        .unwrap_or(0);
+
+    let value = match value.strip_prefix('~') {
+        Some(rest) => format!("{}{}", home, rest),
+        None => value.to_string(),
+    };
+
+    value
+        .replace("${HOME}", &home)
+        .replace("${RUNUSER}", &format!("/run/user/{}", uid))
+        .replace("${CFG}", FIREJAIL_CONFIG_DIR)
+        .into()
+}
+
+/// Includes are looked up in the config of the user, then the system config and lastly next to
+/// the profile which includes them
+fn find_include(include: &Path, dir: &Path) -> Option<PathBuf> {
+    if include.is_absolute() {
+        return Some(include.to_path_buf()).filter(|x| x.exists());
+    }
+
+    let user = std::env::var_os("HOME").map(|x| Path::new(&x).join(".config/firejail"));
+
+    user.into_iter()
+        .chain([PathBuf::from(FIREJAIL_CONFIG_DIR), dir.to_path_buf()])
+        .map(|x| x.join(include))
+        .find(|x| x.exists())
+}
+
+/// What makes the path read only in the sandbox, going by the mounts it already has. `None` if it
+/// already is read only.
+///
+/// bwrap can only remount the path a mount is on, anything beneath it has to be bound again.
+fn read_only(mounts: &[BubMount], path: &Path) -> Result<Option<BubMount>, &'static str> {
+    let mount = mounts
+        .iter()
+        .rev()
+        .filter(|x| {
+            !matches!(
+                x,
+                BubMount::Symlink { .. }
+                    | BubMount::Dir { .. }
+                    | BubMount::File { .. }
+                    | BubMount::Chmod { .. }
+                    | BubMount::RemountRO { .. }
+            )
+        })
+        .find(|x| path.starts_with(x.dest()))
+        .ok_or("nothing is mounted there in the sandbox")?;
+
+    match mount {
+        BubMount::BindRO { .. } | BubMount::BindDataRO { .. } | BubMount::RoOverlay { .. } => {
+            Ok(None)
+        }
+        _ if mount.dest() == path => Ok(Some(BubMount::remount_ro(path))),
+        BubMount::DevBind { src, dest } | BubMount::BindRW { src, dest } => {
+            let host = src.join(path.strip_prefix(dest).unwrap_or(path));
+            match host.exists() {
+                true => Ok(Some(BubMount::bind_ro(host, path))),
+                false => Err("the path doesn't exist"),
+            }
+        }
+        _ => Err("only paths from the host can be made read only"),
+    }
+}
+
+fn has_wildcard(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own for every test, since they run in parallel
+    fn profile_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cellar-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn expands_macros() {
+        let home = std::env::var("HOME").unwrap_or_default();
+
+        assert_eq!(
+            expand("${HOME}/.wine"),
+            PathBuf::from(format!("{}/.wine", home))
+        );
+        assert_eq!(expand("~/.wine"), PathBuf::from(format!("{}/.wine", home)));
+        assert_eq!(expand("${CFG}/x.inc"), Path::new("/etc/firejail/x.inc"));
+        assert!(expand("${RUNUSER}/pulse").starts_with("/run/user/"));
+        assert_eq!(expand("/opt/~x"), Path::new("/opt/~x"));
+    }
+
+    #[test]
+    fn finds_includes() {
+        let dir = profile_dir("finds-includes");
+        let include = dir.join("cellar-test.inc");
+        std::fs::write(&include, "").unwrap();
+
+        assert_eq!(
+            find_include(Path::new("cellar-test.inc"), &dir),
+            Some(include.clone())
+        );
+        assert_eq!(find_include(&include, Path::new("/")), Some(include));
+        assert_eq!(find_include(Path::new("cellar-missing.inc"), &dir), None);
+        assert_eq!(find_include(&dir.join("cellar-missing.inc"), &dir), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_profile_with_includes() {
+        let dir = profile_dir("reads-profile");
+        std::fs::write(
+            dir.join("game.profile"),
+            "# comment\n\
+             include cellar-common.inc\n\
+             include cellar-game.local\n\
+             include cellar-missing.inc\n\
+             noblacklist /opt/game\n\
+             private-etc fonts, ld.so.cache\n\
+             x11 xephyr\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("cellar-common.inc"),
+            "include game.profile\nblacklist /opt/game\nblacklist /mnt\nnonewprivs\n",
+        )
+        .unwrap();
+
+        let profile = FirejailProfile::load(dir.join("game.profile")).unwrap();
+        assert_eq!(
+            profile.entries,
+            [
+                ProfileEntry::Blacklist("/opt/game".into()),
+                ProfileEntry::Blacklist("/mnt".into()),
+                ProfileEntry::Flag("nonewprivs".to_string()),
+                ProfileEntry::NoBlacklist("/opt/game".into()),
+                ProfileEntry::PrivateEtc(vec!["fonts".to_string(), "ld.so.cache".to_string()]),
+            ]
+        );
+        assert_eq!(profile.blacklist(), [Path::new("/mnt")]);
+
+        // The missing .local is skipped like firejail does
+        let lines = profile
+            .unsupported
+            .iter()
+            .map(|x| x.line.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, ["include cellar-missing.inc", "x11 xephyr"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn makes_paths_read_only() {
+        let mounts = [
+            BubMount::bind_ro("/usr", "/usr"),
+            BubMount::tmpfs("/home"),
+            BubMount::bind_rw("/tmp", "/data"),
+            BubMount::dir("/data/new"),
+        ];
+
+        assert!(matches!(
+            read_only(&mounts, Path::new("/usr/share")),
+            Ok(None)
+        ));
+        assert!(matches!(
+            read_only(&mounts, Path::new("/data")),
+            Ok(Some(BubMount::RemountRO { .. }))
+        ));
+        assert!(matches!(
+            read_only(&mounts, Path::new("/home")),
+            Ok(Some(BubMount::RemountRO { .. }))
+        ));
+        assert!(read_only(&mounts, Path::new("/home/user")).is_err());
+        assert!(read_only(&mounts, Path::new("/etc")).is_err());
+
+        // Beneath a bind the path of the host is bound again
+        let dir = profile_dir("read-only");
+        let mounts = [BubMount::bind_rw(&dir, "/data")];
+        match read_only(&mounts, Path::new("/data")) {
+            Ok(Some(BubMount::RemountRO { .. })) => {}
+            other => panic!("{:?}", other),
+        }
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        match read_only(&mounts, Path::new("/data/sub")) {
+            Ok(Some(BubMount::BindRO { src, dest })) => {
+                assert_eq!(src, dir.join("sub"));
+                assert_eq!(dest, Path::new("/data/sub"));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(read_only(&mounts, Path::new("/data/missing")).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn whitelists_read_only() {
+        let dir = profile_dir("whitelists");
+        for sub in ["ro/sub", "rw"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        std::fs::write(
+            dir.join("game.profile"),
+            format!(
+                "read-write {0}/ro/sub\n\
+                 whitelist {0}/ro\n\
+                 whitelist {0}/rw\n\
+                 read-write {0}/rw\n\
+                 blacklist /etc/passwd\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        let profile = FirejailProfile::load(dir.join("game.profile")).unwrap();
+
+        let mut launcher = BubLauncher::default();
+        launcher.user(crate::SandboxUser::new("user", 1000, 1000));
+        let untranslated = profile.apply_bubblewrap(&mut launcher, Path::new("/home"));
+
+        let (ro, rw, sub) = (dir.join("ro"), dir.join("rw"), dir.join("ro/sub"));
+        let mounts = launcher.mounts();
+        assert!(matches!(&mounts[0], BubMount::BindRO { src, dest } if src == &ro && dest == &ro));
+        assert!(matches!(&mounts[1], BubMount::BindRW { src, dest } if src == &rw && dest == &rw));
+        assert!(
+            matches!(&mounts[2], BubMount::BindRW { src, dest } if src == &sub && dest == &sub)
+        );
+
+        // The passwd bwrap writes would undo the blacklist
+        let reasons = untranslated
+            .iter()
+            .map(|x| (x.line.as_str(), x.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            [(
+                "blacklist /etc/passwd",
+                "bubblewrap writes the user and machine id files after it"
+            )]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,315 @@
+use crate::{BubMount, Result, SandboxError};
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+const LANDLOCK_RULE_NET_PORT: u32 = 2;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+const ACCESS_NET_BIND_TCP: u64 = 1 << 0;
+const ACCESS_NET_CONNECT_TCP: u64 = 1 << 1;
+
+/// The only rights which can be granted on a file, the others only make sense for directories
+const ACCESS_FILE: u64 = ACCESS_FS_EXECUTE
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_READ_FILE
+    | ACCESS_FS_TRUNCATE
+    | ACCESS_FS_IOCTL_DEV;
+const ACCESS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+// prctl reads its arguments as unsigned longs, which plain integers aren't through varargs
+const ENABLE: libc::c_ulong = 1;
+const UNUSED: libc::c_ulong = 0;
+
+/// The first ABI which knows about network rules
+const NET_ABI: u32 = 4;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+    handled_access_net: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[repr(C)]
+struct NetPortAttr {
+    allowed_access: u64,
+    port: u64,
+}
+
+/// What a process gets to do with a path and everything beneath it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathAccess {
+    /// Reading and executing files, and listing directories
+    ReadOnly,
+    /// Everything Landlock knows about
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PortAccess {
+    Bind,
+    Connect,
+}
+
+/// Restricts a process to the paths, and optionally the TCP ports, it was granted.
+///
+/// Unlike the other backends this needs no namespaces at all, so it works on hosts which don't
+/// allow unprivileged user namespaces. It also stacks with them, as the rules are applied by the
+/// process inside of the sandbox itself.
+///
+/// Access is the union of every rule for a path and its parents, so a read only path beneath a
+/// read write one can still be written to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LandlockRuleset {
+    paths: Vec<(PathBuf, PathAccess)>,
+    ports: Vec<(u16, PortAccess)>,
+    restrict_network: bool,
+}
+
+/// How much of a [`LandlockRuleset`] the kernel enforces
+#[derive(Debug, Clone)]
+pub struct Enforcement {
+    /// The version of Landlock the kernel supports
+    pub abi: u32,
+    /// Whether the network rules are enforced, which older kernels don't support
+    pub network: bool,
+    /// Granted paths which don't exist, and so were left out
+    pub missing: Vec<PathBuf>,
+}
+
+impl LandlockRuleset {
+    /// Grants the paths the mounts of a bwrap sandbox put in place, for stacking it on top of one
+    pub fn from_mounts(mounts: &[BubMount]) -> LandlockRuleset {
+        let mut ruleset = LandlockRuleset::default();
+
+        for mount in mounts {
+            match mount {
+                // These only change what is already there, the mount itself already says where
+                BubMount::Symlink { .. } | BubMount::Chmod { .. } | BubMount::RemountRO { .. } => {}
+                BubMount::BindRO { .. }
+                | BubMount::BindDataRO { .. }
+                | BubMount::RoOverlay { .. } => {
+                    ruleset.read_only(mount.dest());
+                }
+                _ => {
+                    ruleset.read_write(mount.dest());
+                }
+            }
+        }
+
+        ruleset
+    }
+
+    pub fn read_only<T: Into<PathBuf>>(&mut self, path: T) -> &mut LandlockRuleset {
+        self.paths.push((path.into(), PathAccess::ReadOnly));
+        self
+    }
+
+    pub fn read_write<T: Into<PathBuf>>(&mut self, path: T) -> &mut LandlockRuleset {
+        self.paths.push((path.into(), PathAccess::ReadWrite));
+        self
+    }
+
+    /// Only allows binding and connecting to the TCP ports which were granted, where the kernel
+    /// supports it
+    pub fn restrict_network(&mut self, restrict: bool) -> &mut LandlockRuleset {
+        self.restrict_network = restrict;
+        self
+    }
+
+    pub fn port(&mut self, port: u16, access: PortAccess) -> &mut LandlockRuleset {
+        self.ports.push((port, access));
+        self
+    }
+
+    pub fn paths(&self) -> &[(PathBuf, PathAccess)] {
+        &self.paths
+    }
+
+    pub fn restricts_network(&self) -> bool {
+        self.restrict_network
+    }
+
+    /// Restricts the calling process and everything it starts from then on, which can't be undone.
+    ///
+    /// This also sets `no_new_privs`, as the kernel requires it for unprivileged processes.
+    pub fn restrict_self(&self) -> Result<Enforcement> {
+        let abi = abi()?.ok_or(SandboxError::LandlockUnsupported)?;
+        let network = self.restrict_network && abi >= NET_ABI;
+
+        let handled_access_fs = handled_access_fs(abi);
+        let attr = RulesetAttr {
+            handled_access_fs,
+            handled_access_net: match network {
+                true => ACCESS_NET_BIND_TCP | ACCESS_NET_CONNECT_TCP,
+                false => 0,
+            },
+        };
+
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let ruleset = unsafe { File::from_raw_fd(fd as i32) };
+
+        let mut missing = Vec::new();
+        for (path, access) in &self.paths {
+            let parent = match File::options()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+                .open(path)
+            {
+                Ok(parent) => parent,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    missing.push(path.clone());
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            let mut allowed_access = match access {
+                PathAccess::ReadOnly => ACCESS_READ,
+                PathAccess::ReadWrite => handled_access_fs,
+            };
+            if !parent.metadata()?.is_dir() {
+                allowed_access &= ACCESS_FILE;
+            }
+
+            let rule = PathBeneathAttr {
+                allowed_access: allowed_access & handled_access_fs,
+                parent_fd: parent.as_raw_fd(),
+            };
+            add_rule(&ruleset, LANDLOCK_RULE_PATH_BENEATH, &rule)?;
+        }
+
+        if network {
+            for (port, access) in &self.ports {
+                let rule = NetPortAttr {
+                    allowed_access: match access {
+                        PortAccess::Bind => ACCESS_NET_BIND_TCP,
+                        PortAccess::Connect => ACCESS_NET_CONNECT_TCP,
+                    },
+                    port: *port as u64,
+                };
+                add_rule(&ruleset, LANDLOCK_RULE_NET_PORT, &rule)?;
+            }
+        }
+
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, ENABLE, UNUSED, UNUSED, UNUSED) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(Enforcement {
+            abi,
+            network,
+            missing,
+        })
+    }
+}
+
+/// The version of Landlock the kernel supports, if it does at all
+pub fn abi() -> Result<Option<u32>> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+
+    match abi {
+        abi if abi >= 0 => Ok(Some(abi as u32)),
+        _ => match io::Error::last_os_error().raw_os_error() {
+            // Either the kernel was built without it, or it isn't in the list of enabled LSMs
+            Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => Ok(None),
+            _ => Err(io::Error::last_os_error().into()),
+        },
+    }
+}
+
+/// Every filesystem right the ABI knows about, newer ones added more of them
+fn handled_access_fs(abi: u32) -> u64 {
+    let mut access = ACCESS_FS_EXECUTE
+        | ACCESS_FS_WRITE_FILE
+        | ACCESS_FS_READ_FILE
+        | ACCESS_FS_READ_DIR
+        | ACCESS_FS_REMOVE_DIR
+        | ACCESS_FS_REMOVE_FILE
+        | ACCESS_FS_MAKE_CHAR
+        | ACCESS_FS_MAKE_DIR
+        | ACCESS_FS_MAKE_REG
+        | ACCESS_FS_MAKE_SOCK
+        | ACCESS_FS_MAKE_FIFO
+        | ACCESS_FS_MAKE_BLOCK
+        | ACCESS_FS_MAKE_SYM;
+
+    if abi >= 2 {
+        access |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        access |= ACCESS_FS_TRUNCATE;
+    }
+    if abi >= 5 {
+        access |= ACCESS_FS_IOCTL_DEV;
+    }
+
+    access
+}
+
+fn add_rule<T>(ruleset: &File, kind: u32, rule: &T) -> Result<()> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            kind,
+            rule as *const T,
+            0,
+        )
+    };
+
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error().into()),
+    }
+}
@@ -1,37 +1,259 @@
 #![allow(unused_imports)]
 pub mod bubblewrap;
+pub mod detect;
+pub mod fd;
 pub mod firejail;
+pub mod firejail_profile;
+pub mod landlock;
+mod native;
+pub mod oci;
+pub mod render;
+pub mod seccomp;
+pub mod status;
+pub mod systemd;
+pub mod validate;
+pub mod x11;
 
-pub use self::bubblewrap::{BubLauncher, BubMount};
-pub use self::firejail::{FirejailLauncher, X11Sandbox};
+pub use self::bubblewrap::{BubLauncher, BubMount, SandboxUser};
+pub use self::detect::{Bwrap, BwrapFeature, BwrapVersion};
+pub use self::fd::DataFd;
+pub use self::firejail::{FirejailLauncher, FirejailSandbox, Rlimit, X11Sandbox};
+pub use self::firejail_profile::{FirejailProfile, ProfileEntry, Untranslated};
+pub use self::landlock::{Enforcement, LandlockRuleset, PathAccess, PortAccess};
+pub use self::oci::{OciBundle, OciSpec};
+pub use self::seccomp::{SeccompAction, SeccompPolicy, SeccompRule};
+pub use self::status::{Process, SandboxHandle, SandboxInfo, SandboxStatus};
+pub use self::systemd::{ScopeStats, SystemdScope};
+pub use self::validate::{Diagnostic, Severity};
+pub use self::x11::{NestedServer, X11Server};
+
+use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub type Result<T, E = SandboxError> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum SandboxError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("unknown syscall \"{0}\"")]
+    UnknownSyscall(String),
+
+    #[error("syscalls have no argument {0}")]
+    SeccompArgument(u8),
+
+    #[error("seccomp program is too large ({0} instructions)")]
+    SeccompTooLarge(usize),
+
+    #[error("unable to run bwrap at {0}")]
+    BwrapMissing(std::path::PathBuf),
+
+    #[error("unable to parse bwrap version from \"{0}\"")]
+    BwrapVersion(String),
+
+    #[error("firejail exited with {0}")]
+    FirejailFailed(std::process::ExitStatus),
+
+    #[error("systemctl exited with {0}")]
+    SystemdFailed(std::process::ExitStatus),
+
+    #[error("unable to set up the sandbox while {step}: {source}")]
+    Setup {
+        step: String,
+        source: std::io::Error,
+    },
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+    #[error("the native sandbox does not support {0}")]
+    NativeUnsupported(&'static str),
+
+    #[error("the sandbox with pid {0} is still running")]
+    StillRunning(i32),
+
+    #[error("bwrap exited before reporting the sandbox status")]
+    StatusMissing,
+
+    #[error("env var \"{0}\" is passed through, but isn't set")]
+    MissingEnv(String),
+
+    #[error("the kernel doesn't support landlock")]
+    LandlockUnsupported,
+
+    #[error("no display is free for a nested X server")]
+    NoFreeDisplay,
+
+    #[error("{0:?} exited or took too long before its display was ready")]
+    X11Server(X11Server),
+
+    #[error("bwrap {version} does not support {}", .feature.flag())]
+    Unsupported {
+        feature: BwrapFeature,
+        version: BwrapVersion,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum EnvVar {
-    /// Uses the env var in the environment when calling a command which would use it
+    /// Uses the env var in the environment when calling a command which would use it, failing if
+    /// it isn't set
     Pass(String),
+    /// Like [`EnvVar::Pass`], but falls back to the value if the env var isn't set
+    PassOr(String, String),
+    /// Like [`EnvVar::Pass`], but leaves the env var alone if it isn't set
+    PassIfSet(String),
     KeyValue(String, String),
+    Unset(String),
+    /// Adds onto the end of the current value, or sets it if there is none
+    Append {
+        key: String,
+        value: String,
+        separator: String,
+    },
+    /// Adds onto the start of the current value, or sets it if there is none
+    Prepend {
+        key: String,
+        value: String,
+        separator: String,
+    },
 }
 
 impl EnvVar {
+    pub fn append<K, V, S>(key: K, value: V, separator: S) -> EnvVar
+    where
+        K: Into<String>,
+        V: Into<String>,
+        S: Into<String>,
+    {
+        EnvVar::Append {
+            key: key.into(),
+            value: value.into(),
+            separator: separator.into(),
+        }
+    }
+
+    pub fn prepend<K, V, S>(key: K, value: V, separator: S) -> EnvVar
+    where
+        K: Into<String>,
+        V: Into<String>,
+        S: Into<String>,
+    {
+        EnvVar::Prepend {
+            key: key.into(),
+            value: value.into(),
+            separator: separator.into(),
+        }
+    }
+
     pub fn key(&self) -> &str {
         match self {
-            EnvVar::Pass(k) | EnvVar::KeyValue(k, ..) => k,
+            EnvVar::Pass(k)
+            | EnvVar::PassOr(k, ..)
+            | EnvVar::PassIfSet(k)
+            | EnvVar::KeyValue(k, ..)
+            | EnvVar::Unset(k)
+            | EnvVar::Append { key: k, .. }
+            | EnvVar::Prepend { key: k, .. } => k,
         }
     }
 
-    pub fn to_key_value(self) -> (String, String) {
+    /// Whether the env var is passed through but isn't set here, regardless of what happens then
+    pub fn is_missing(&self) -> bool {
+        match self {
+            EnvVar::Pass(k) | EnvVar::PassOr(k, ..) | EnvVar::PassIfSet(k) => {
+                std::env::var_os(k).is_none()
+            }
+            _ => false,
+        }
+    }
+
+    /// The value the env var ends up with when it is `current` beforehand, `None` if it should be
+    /// removed
+    pub fn resolve(self, current: Option<String>) -> Result<Option<String>> {
+        Ok(match self {
+            EnvVar::Pass(k) => Some(std::env::var(&k).map_err(|_| SandboxError::MissingEnv(k))?),
+            EnvVar::PassOr(k, default) => Some(std::env::var(&k).unwrap_or(default)),
+            EnvVar::PassIfSet(k) => std::env::var(&k).ok().or(current),
+            EnvVar::KeyValue(_, v) => Some(v),
+            EnvVar::Unset(_) => None,
+            EnvVar::Append {
+                value, separator, ..
+            } => Some(match current {
+                Some(current) if !current.is_empty() => current + &separator + &value,
+                _ => value,
+            }),
+            EnvVar::Prepend {
+                value, separator, ..
+            } => Some(match current {
+                Some(current) if !current.is_empty() => value + &separator + &current,
+                _ => value,
+            }),
+        })
+    }
+
+    /// Looks up passed through env vars here, for when the rest is resolved somewhere else, like
+    /// inside of the sandbox. Gives back `None` for env vars which are left alone.
+    pub fn capture(self) -> Result<Option<EnvVar>> {
         match self {
-            EnvVar::Pass(k) => {
-                let val = std::env::var(&k).expect("failed to get env var");
-                (k, val)
+            EnvVar::Pass(..) | EnvVar::PassOr(..) | EnvVar::PassIfSet(..) => {
+                let key = self.key().to_string();
+                Ok(self
+                    .resolve(None)?
+                    .map(|value| EnvVar::KeyValue(key, value)))
             }
-            EnvVar::KeyValue(k, v) => (k, v),
+            var => Ok(Some(var)),
         }
     }
 }
 
+impl std::fmt::Display for EnvVar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvVar::Pass(k) => write!(f, "{} (from the host)", k),
+            EnvVar::PassOr(k, default) => write!(f, "{} (from the host, or {})", k, default),
+            EnvVar::PassIfSet(k) => write!(f, "{} (from the host, if set)", k),
+            EnvVar::KeyValue(k, v) => write!(f, "{}={}", k, v),
+            EnvVar::Unset(k) => write!(f, "unset {}", k),
+            EnvVar::Append {
+                key,
+                value,
+                separator,
+            } => write!(f, "{0}=${0}{1}{2}", key, separator, value),
+            EnvVar::Prepend {
+                key,
+                value,
+                separator,
+            } => write!(f, "{0}={2}{1}${0}", key, separator, value),
+        }
+    }
+}
+
+/// Resolves the env vars in order, so each one sees the changes made by the ones before it. Env
+/// vars which haven't been touched yet are looked up with `inherited`.
+///
+/// Gives back what each env var has to be set to, `None` meaning it has to be removed.
+pub fn resolve_env<F>(vars: Vec<EnvVar>, inherited: F) -> Result<Vec<(String, Option<String>)>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut current = HashMap::<String, Option<String>>::new();
+
+    vars.into_iter()
+        .map(|var| {
+            let key = var.key().to_string();
+            let before = match current.get(&key) {
+                Some(value) => value.clone(),
+                None => inherited(&key),
+            };
+
+            let value = var.resolve(before)?;
+            current.insert(key.clone(), value.clone());
+            Ok((key, value))
+        })
+        .collect()
+}
+
 impl Into<EnvVar> for &'static str {
     fn into(self) -> EnvVar {
         EnvVar::Pass(self.to_string())
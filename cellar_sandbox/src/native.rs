@@ -0,0 +1,1219 @@
+use crate::status::SandboxStatus;
+use crate::{BubMount, Result};
+
+use std::collections::HashSet;
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The tmpfs the sandbox is put together in, which only exists in its own mount namespace
+const BASE_PATH: &str = "/tmp";
+/// Where the new root is put together, once the tmpfs is the root
+const NEW_ROOT: &str = "/newroot";
+/// Where the root of the host is, until the new root is done
+const OLD_ROOT: &str = "/oldroot";
+
+/// Flags of a mount which a user namespace isn't allowed to clear, as they were set from outside
+const LOCKED_FLAGS: [(libc::c_ulong, libc::c_ulong); 7] = [
+    (libc::ST_RDONLY, libc::MS_RDONLY),
+    (libc::ST_NOSUID, libc::MS_NOSUID),
+    (libc::ST_NODEV, libc::MS_NODEV),
+    (libc::ST_NOEXEC, libc::MS_NOEXEC),
+    (libc::ST_NOATIME, libc::MS_NOATIME),
+    (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+    (libc::ST_RELATIME, libc::MS_RELATIME),
+];
+
+/// What the sandbox gets set up with, see [`BubLauncher::native_command`](crate::BubLauncher)
+#[derive(Debug)]
+pub(crate) struct NativeOptions {
+    pub mounts: Vec<BubMount>,
+    pub unshare_net: bool,
+    pub unshare_ipc: bool,
+    pub unshare_uts: bool,
+    pub unshare_cgroups: bool,
+    pub hostname: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub new_session: bool,
+    pub die_with_parent: bool,
+    pub disable_userns: bool,
+    pub drop_caps: bool,
+    pub seccomp: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+enum Step {
+    DieWithParent,
+    NewSession,
+    Unshare(libc::c_int),
+    /// Writes to a file which has to exist already, like the ones in `/proc`
+    WriteExisting {
+        path: CString,
+        content: Vec<u8>,
+    },
+    Hostname(CString),
+    Mount {
+        src: Option<CString>,
+        dest: CString,
+        fstype: Option<CString>,
+        flags: libc::c_ulong,
+        data: Option<CString>,
+    },
+    /// Adds flags to a bind mount, keeping the ones it already has. Submounts which can't be
+    /// accessed are left as they are, like bwrap does.
+    Remount {
+        path: CString,
+        flags: libc::c_ulong,
+        submount: bool,
+    },
+    /// Creates a directory, unless there already is one
+    Dir {
+        path: CString,
+        mode: u32,
+    },
+    /// Creates a directory or an empty file, depending on what `src` is, for it to be mounted on
+    MountPoint {
+        src: CString,
+        dest: CString,
+    },
+    Write {
+        path: CString,
+        content: Vec<u8>,
+        mode: u32,
+    },
+    Symlink {
+        target: CString,
+        link: CString,
+    },
+    Chmod {
+        path: CString,
+        mode: u32,
+    },
+    Chdir(CString),
+    /// Makes `new_root` the root, with the current one moved to `put_old`
+    PivotRoot {
+        new_root: CString,
+        put_old: CString,
+    },
+    Detach(CString),
+    DropCaps,
+    Seccomp(Vec<libc::sock_filter>),
+}
+
+/// A step along with the start of the status record which reports it failing
+#[derive(Debug)]
+struct Record {
+    step: Step,
+    error: Vec<u8>,
+}
+
+/// Everything the sandbox gets set up with, prepared up front, as nothing may be allocated
+/// between forking and exec.
+///
+/// `outer` runs in the process spawned for the command, which unshares the namespaces. That one
+/// becomes the monitor of the sandbox, reporting its status like bwrap would, while its child
+/// becomes pid 1 and runs `inner` before going on with the exec.
+#[derive(Debug)]
+struct Setup {
+    outer: Vec<Record>,
+    inner: Vec<Record>,
+    fork_error: Vec<u8>,
+    status: File,
+}
+
+/// Builds the list of steps, making sure parent directories are only created once
+#[derive(Debug, Default)]
+struct Plan {
+    outer: Vec<Record>,
+    inner: Vec<Record>,
+    dirs: HashSet<PathBuf>,
+    /// The mount points of the host, which binds take along beneath them
+    host_mounts: Vec<PathBuf>,
+    /// The mount points in the sandbox so far, in the order they're mounted
+    mounts: Vec<PathBuf>,
+}
+
+/// Creates a command which sets up the sandbox itself and then runs `program` in it, reporting
+/// what it's doing through the status pipe the same way bwrap does
+pub(crate) fn command(program: &OsStr, options: NativeOptions) -> Result<(Command, SandboxStatus)> {
+    let (status, write) = SandboxStatus::pipe()?;
+    let mut plan = Plan {
+        host_mounts: mount_points(&std::fs::read_to_string("/proc/self/mountinfo")?),
+        ..Plan::default()
+    };
+
+    if options.die_with_parent {
+        plan.outer(Step::DieWithParent, "setting the parent death signal");
+    }
+
+    let mut flags = libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+    for (unshare, flag) in [
+        (options.unshare_net, libc::CLONE_NEWNET),
+        (options.unshare_ipc, libc::CLONE_NEWIPC),
+        (options.unshare_uts, libc::CLONE_NEWUTS),
+        (options.unshare_cgroups, libc::CLONE_NEWCGROUP),
+    ] {
+        if unshare {
+            flags |= flag;
+        }
+    }
+    plan.outer(Step::Unshare(flags), "creating the namespaces");
+
+    let (outer_uid, outer_gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let (uid, gid) = (
+        options.uid.unwrap_or(outer_uid),
+        options.gid.unwrap_or(outer_gid),
+    );
+    plan.id_maps(true, (uid, outer_uid), (gid, outer_gid))?;
+
+    if options.die_with_parent {
+        plan.inner(Step::DieWithParent, "setting the parent death signal");
+    }
+    if options.new_session {
+        plan.inner(Step::NewSession, "starting a new session");
+    }
+    if let Some(hostname) = options.hostname {
+        plan.inner(
+            Step::Hostname(CString::new(hostname.clone()).map_err(invalid)?),
+            format!("setting the hostname to {}", hostname),
+        );
+    }
+
+    plan.enter_base()?;
+    for (idx, mount) in options.mounts.into_iter().enumerate() {
+        plan.mount(idx, resolve(mount))?;
+    }
+    plan.enter_root()?;
+
+    if options.disable_userns {
+        // Nested namespaces count against the limit of every parent, and this one is used up
+        // by the namespace which is created right after
+        plan.inner(
+            Step::WriteExisting {
+                path: cstring("/proc/sys/user/max_user_namespaces")?,
+                content: b"1".to_vec(),
+            },
+            "limiting the user namespaces",
+        );
+        plan.inner(
+            Step::Unshare(libc::CLONE_NEWUSER),
+            "creating the nested user namespace",
+        );
+        plan.id_maps(false, (uid, uid), (gid, gid))?;
+    }
+
+    if options.drop_caps {
+        plan.inner(Step::DropCaps, "dropping the capabilities");
+    }
+
+    if let Some(program) = options.seccomp {
+        let filters = program
+            .chunks_exact(8)
+            .map(|x| libc::sock_filter {
+                code: u16::from_ne_bytes([x[0], x[1]]),
+                jt: x[2],
+                jf: x[3],
+                k: u32::from_ne_bytes([x[4], x[5], x[6], x[7]]),
+            })
+            .collect();
+        plan.inner(Step::Seccomp(filters), "loading the seccomp filter");
+    }
+
+    let setup = Setup {
+        outer: plan.outer,
+        inner: plan.inner,
+        fork_error: error_record("starting pid 1 of the sandbox"),
+        status: write,
+    };
+
+    let mut cmd = Command::new(program);
+    unsafe {
+        cmd.pre_exec(move || setup.enter());
+    }
+
+    Ok((cmd, status))
+}
+
+impl Plan {
+    fn outer<T: Into<String>>(&mut self, step: Step, what: T) {
+        self.outer.push(Record {
+            step,
+            error: error_record(&what.into()),
+        });
+    }
+
+    fn inner<T: Into<String>>(&mut self, step: Step, what: T) {
+        self.inner.push(Record {
+            step,
+            error: error_record(&what.into()),
+        });
+    }
+
+    /// Maps the ids of the namespace which was just created, `(inside, outside)`
+    fn id_maps(&mut self, outer: bool, uid: (u32, u32), gid: (u32, u32)) -> Result<()> {
+        let steps = [
+            ("/proc/self/setgroups", "deny".to_string()),
+            ("/proc/self/uid_map", format!("{} {} 1\n", uid.0, uid.1)),
+            ("/proc/self/gid_map", format!("{} {} 1\n", gid.0, gid.1)),
+        ];
+
+        for (path, content) in steps {
+            let step = Step::WriteExisting {
+                path: cstring(path)?,
+                content: content.into_bytes(),
+            };
+            let what = format!("writing {}", path);
+
+            match outer {
+                true => self.outer(step, what),
+                false => self.inner(step, what),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes a tmpfs the root, with the one of the host beneath it to take the mounts from
+    fn enter_base(&mut self) -> Result<()> {
+        let base = Path::new(BASE_PATH);
+
+        self.inner(
+            Step::Mount {
+                src: None,
+                dest: cstring("/")?,
+                fstype: None,
+                flags: libc::MS_SLAVE | libc::MS_REC,
+                data: None,
+            },
+            "keeping mounts from propagating to the host",
+        );
+        self.inner(
+            Step::Mount {
+                src: Some(cstring("tmpfs")?),
+                dest: cstring(base)?,
+                fstype: Some(cstring("tmpfs")?),
+                flags: libc::MS_NOSUID | libc::MS_NODEV,
+                data: Some(cstring("mode=0755")?),
+            },
+            format!("mounting the tmpfs on {}", BASE_PATH),
+        );
+
+        for dir in [NEW_ROOT, OLD_ROOT] {
+            let path = base.join(dir.trim_start_matches('/'));
+            self.inner(
+                Step::Dir {
+                    path: cstring(&path)?,
+                    mode: 0o755,
+                },
+                format!("creating {}", path.display()),
+            );
+        }
+
+        self.inner(
+            Step::Chdir(cstring(base)?),
+            format!("changing to {}", BASE_PATH),
+        );
+        self.inner(
+            Step::PivotRoot {
+                new_root: cstring(".")?,
+                put_old: cstring(OLD_ROOT.trim_start_matches('/'))?,
+            },
+            format!("moving the root to {}", BASE_PATH),
+        );
+        self.inner(Step::Chdir(cstring("/")?), "changing to /");
+
+        self.inner(
+            Step::Mount {
+                src: Some(cstring("tmpfs")?),
+                dest: cstring(NEW_ROOT)?,
+                fstype: Some(cstring("tmpfs")?),
+                flags: libc::MS_NOSUID | libc::MS_NODEV,
+                data: Some(cstring("mode=0755")?),
+            },
+            "mounting the new root",
+        );
+        self.dirs.insert(PathBuf::from(NEW_ROOT));
+
+        Ok(())
+    }
+
+    /// Swaps the root of the host for the new one
+    fn enter_root(&mut self) -> Result<()> {
+        self.inner(
+            Step::Detach(cstring(OLD_ROOT)?),
+            "unmounting the root of the host",
+        );
+        self.inner(
+            Step::Chdir(cstring(NEW_ROOT)?),
+            format!("changing to {}", NEW_ROOT),
+        );
+        self.inner(
+            Step::PivotRoot {
+                new_root: cstring(".")?,
+                put_old: cstring(".")?,
+            },
+            "moving the root to the new one",
+        );
+        self.inner(Step::Detach(cstring(".")?), "unmounting the tmpfs");
+        self.inner(Step::Chdir(cstring("/")?), "changing to /");
+
+        Ok(())
+    }
+
+    fn mount(&mut self, idx: usize, mount: BubMount) -> Result<()> {
+        // Binds keep track of their mount points themselves, along with what comes with them
+        let mount_point = match &mount {
+            BubMount::TmpFs { path, .. } | BubMount::Proc { path } => Some(path.clone()),
+            BubMount::Overlay { dest, .. }
+            | BubMount::TmpOverlay { dest, .. }
+            | BubMount::RoOverlay { dest, .. } => Some(dest.clone()),
+            _ => None,
+        };
+
+        match mount {
+            BubMount::DevBind { src, dest } => {
+                self.bind(&host_path(&src), &src, &dest, libc::MS_NOSUID)?
+            }
+            BubMount::BindRO { src, dest } => self.bind(
+                &host_path(&src),
+                &src,
+                &dest,
+                libc::MS_NOSUID | libc::MS_NODEV | libc::MS_RDONLY,
+            )?,
+            BubMount::BindRW { src, dest } => self.bind(
+                &host_path(&src),
+                &src,
+                &dest,
+                libc::MS_NOSUID | libc::MS_NODEV,
+            )?,
+
+            BubMount::Symlink { src, dest } => {
+                self.parents(&dest)?;
+                self.inner(
+                    Step::Symlink {
+                        target: cstring(&src)?,
+                        link: cstring(new_path(&dest))?,
+                    },
+                    format!("linking {} to {}", dest.display(), src.display()),
+                );
+            }
+
+            BubMount::TmpFs { path, perms, size } => {
+                let mut data = format!("mode={:04o}", perms.unwrap_or(0o755));
+                if let Some(size) = size {
+                    data.push_str(&format!(",size={}", size));
+                }
+
+                self.dir(&path, 0o755)?;
+                self.inner(
+                    Step::Mount {
+                        src: Some(cstring("tmpfs")?),
+                        dest: cstring(new_path(&path))?,
+                        fstype: Some(cstring("tmpfs")?),
+                        flags: libc::MS_NOSUID | libc::MS_NODEV,
+                        data: Some(CString::new(data).map_err(invalid)?),
+                    },
+                    format!("mounting a tmpfs on {}", path.display()),
+                );
+            }
+            BubMount::Proc { path } => {
+                self.dir(&path, 0o755)?;
+                self.inner(
+                    Step::Mount {
+                        src: Some(cstring("proc")?),
+                        dest: cstring(new_path(&path))?,
+                        fstype: Some(cstring("proc")?),
+                        flags: libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                        data: None,
+                    },
+                    format!("mounting proc on {}", path.display()),
+                );
+            }
+
+            BubMount::Dir { path, perms } => {
+                self.dir(&path, 0o755)?;
+                if let Some(mode) = perms {
+                    self.chmod(&path, mode)?;
+                }
+            }
+            BubMount::File {
+                content,
+                path,
+                perms,
+            } => {
+                self.parents(&path)?;
+                self.write(&new_path(&path), &path, content, perms.unwrap_or(0o666))?;
+            }
+            BubMount::BindData {
+                content,
+                path,
+                perms,
+            } => {
+                let data = Path::new("/").join(format!("data-{}", idx));
+                self.write(&data, &path, content, perms.unwrap_or(0o600))?;
+                self.bind(&data, &path, &path, libc::MS_NOSUID | libc::MS_NODEV)?;
+            }
+            BubMount::BindDataRO {
+                content,
+                path,
+                perms,
+            } => {
+                let data = Path::new("/").join(format!("data-{}", idx));
+                self.write(&data, &path, content, perms.unwrap_or(0o600))?;
+                self.bind(
+                    &data,
+                    &path,
+                    &path,
+                    libc::MS_NOSUID | libc::MS_NODEV | libc::MS_RDONLY,
+                )?;
+            }
+
+            BubMount::Overlay {
+                sources,
+                upper,
+                work,
+                dest,
+            } => {
+                let options = format!(
+                    "{},upperdir={},workdir={}",
+                    lower_dirs(&sources),
+                    escape(&host_path(&upper)),
+                    escape(&host_path(&work)),
+                );
+                self.overlay(&dest, options)?;
+            }
+            BubMount::TmpOverlay { sources, dest } => {
+                // The upper and work directories have to be on the same filesystem, the tmpfs
+                // everything is put together in works for that
+                let base = Path::new("/").join(format!("overlay-{}", idx));
+                let (upper, work) = (base.join("upper"), base.join("work"));
+                for dir in [&base, &upper, &work] {
+                    self.inner(
+                        Step::Dir {
+                            path: cstring(dir)?,
+                            mode: 0o755,
+                        },
+                        format!("creating {}", dir.display()),
+                    );
+                }
+
+                let options = format!(
+                    "{},upperdir={},workdir={}",
+                    lower_dirs(&sources),
+                    escape(&upper),
+                    escape(&work),
+                );
+                self.overlay(&dest, options)?;
+            }
+            BubMount::RoOverlay { sources, dest } => self.overlay(&dest, lower_dirs(&sources))?,
+
+            BubMount::Chmod { mode, path } => self.chmod(&path, mode)?,
+            BubMount::RemountRO { path } => {
+                let submounts = self
+                    .mounts
+                    .iter()
+                    .filter(|x| x.starts_with(&path) && **x != path)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                self.remount(&path, &submounts, libc::MS_RDONLY)?;
+            }
+        }
+
+        self.mounts.extend(mount_point);
+
+        Ok(())
+    }
+
+    /// `src` is where it is right now, `shown` is how the mount refers to it
+    fn bind(&mut self, src: &Path, shown: &Path, dest: &Path, flags: libc::c_ulong) -> Result<()> {
+        let target = cstring(new_path(dest))?;
+        self.parents(dest)?;
+
+        self.inner(
+            Step::MountPoint {
+                src: cstring(src)?,
+                dest: target.clone(),
+            },
+            format!("binding {} to {}", shown.display(), dest.display()),
+        );
+        self.inner(
+            Step::Mount {
+                src: Some(cstring(src)?),
+                dest: target.clone(),
+                fstype: None,
+                flags: libc::MS_BIND | libc::MS_REC,
+                data: None,
+            },
+            format!("binding {} to {}", shown.display(), dest.display()),
+        );
+        self.mounts.push(dest.to_path_buf());
+
+        // Anything mounted beneath the source on the host comes along with MS_REC, and has to get
+        // the flags as well, or it would stay writable
+        let submounts = match src.strip_prefix(OLD_ROOT) {
+            Ok(host) => {
+                let host = Path::new("/").join(host);
+                self.host_mounts
+                    .iter()
+                    .filter_map(|x| x.strip_prefix(&host).ok())
+                    .filter(|x| !x.as_os_str().is_empty())
+                    .map(|x| dest.join(x))
+                    .collect()
+            }
+            Err(_) => Vec::new(),
+        };
+        self.mounts.extend(submounts.iter().cloned());
+
+        self.remount(dest, &submounts, flags)
+    }
+
+    fn remount(&mut self, path: &Path, submounts: &[PathBuf], flags: libc::c_ulong) -> Result<()> {
+        let what = match flags & libc::MS_RDONLY {
+            0 => "remounting",
+            _ => "remounting read only",
+        };
+
+        self.inner(
+            Step::Remount {
+                path: cstring(new_path(path))?,
+                flags,
+                submount: false,
+            },
+            format!("{} {}", what, path.display()),
+        );
+        for submount in submounts {
+            self.inner(
+                Step::Remount {
+                    path: cstring(new_path(submount))?,
+                    flags,
+                    submount: true,
+                },
+                format!("{} {}", what, submount.display()),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Overlays in a user namespace keep their metadata in `user.` xattrs, the `trusted.` ones
+    /// can't be set from in there
+    fn overlay(&mut self, dest: &Path, options: String) -> Result<()> {
+        self.dir(dest, 0o755)?;
+        self.inner(
+            Step::Mount {
+                src: Some(cstring("overlay")?),
+                dest: cstring(new_path(dest))?,
+                fstype: Some(cstring("overlay")?),
+                flags: 0,
+                data: Some(CString::new(format!("{},userxattr", options)).map_err(invalid)?),
+            },
+            format!("mounting an overlay on {}", dest.display()),
+        );
+
+        Ok(())
+    }
+
+    fn write(&mut self, path: &Path, shown: &Path, content: String, mode: u32) -> Result<()> {
+        self.inner(
+            Step::Write {
+                path: cstring(path)?,
+                content: content.into_bytes(),
+                mode,
+            },
+            format!("writing {}", shown.display()),
+        );
+
+        // The umask would get in the way otherwise
+        self.inner(
+            Step::Chmod {
+                path: cstring(path)?,
+                mode,
+            },
+            format!("changing the permissions of {}", shown.display()),
+        );
+
+        Ok(())
+    }
+
+    fn chmod(&mut self, path: &Path, mode: u32) -> Result<()> {
+        self.inner(
+            Step::Chmod {
+                path: cstring(new_path(path))?,
+                mode,
+            },
+            format!("changing the permissions of {}", path.display()),
+        );
+
+        Ok(())
+    }
+
+    fn dir(&mut self, path: &Path, mode: u32) -> Result<()> {
+        self.parents(path)?;
+
+        if self.dirs.insert(new_path(path)) {
+            self.inner(
+                Step::Dir {
+                    path: cstring(new_path(path))?,
+                    mode,
+                },
+                format!("creating {}", path.display()),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Creates the directories leading up to the path in the new root, like bwrap does
+    fn parents(&mut self, path: &Path) -> Result<()> {
+        let mut ancestors = path
+            .ancestors()
+            .skip(1)
+            .filter(|x| x.parent().is_some())
+            .collect::<Vec<_>>();
+        ancestors.reverse();
+
+        for dir in ancestors {
+            if self.dirs.insert(new_path(dir)) {
+                self.inner(
+                    Step::Dir {
+                        path: cstring(new_path(dir))?,
+                        mode: 0o755,
+                    },
+                    format!("creating {}", dir.display()),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Setup {
+    /// Runs in the spawned process, returning only in pid 1 of the sandbox, ready for the exec
+    fn enter(&self) -> io::Result<()> {
+        let status = self.status.as_raw_fd();
+        run(&self.outer, status)?;
+
+        match unsafe { libc::fork() } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                write_record(status, &self.fork_error, errno(&err), b"}}\n");
+                Err(err)
+            }
+            0 => run(&self.inner, status),
+            child => self.monitor(child),
+        }
+    }
+
+    /// Waits for pid 1 of the sandbox, and exits the way it does
+    fn monitor(&self, child: libc::pid_t) -> ! {
+        let status = self.status.as_raw_fd();
+
+        // Anything else held open here, like the pipe std uses to find out whether the exec
+        // worked, would keep the other end waiting until the sandbox exits
+        close_other_fds(status);
+        write_record(status, b"{\"child-pid\":", child as i64, b"}\n");
+
+        let mut wait = 0;
+        while unsafe { libc::waitpid(child, &mut wait, 0) } < 0 {
+            if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                break;
+            }
+        }
+
+        let code = if libc::WIFEXITED(wait) {
+            libc::WEXITSTATUS(wait)
+        } else if libc::WIFSIGNALED(wait) {
+            128 + libc::WTERMSIG(wait)
+        } else {
+            1
+        };
+        write_record(status, b"{\"exit-code\":", code as i64, b"}\n");
+
+        unsafe { libc::_exit(code) }
+    }
+}
+
+impl Step {
+    fn run(&self) -> io::Result<()> {
+        unsafe {
+            match self {
+                Step::DieWithParent => check(prctl(
+                    libc::PR_SET_PDEATHSIG,
+                    libc::SIGKILL as libc::c_ulong,
+                    0,
+                )),
+                Step::NewSession => check(libc::setsid()),
+                Step::Unshare(flags) => check(libc::unshare(*flags)),
+                Step::WriteExisting { path, content } => {
+                    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    check(fd)?;
+                    let result = write_all(fd, content);
+                    libc::close(fd);
+                    result
+                }
+                Step::Hostname(name) => {
+                    check(libc::sethostname(name.as_ptr(), name.as_bytes().len()))
+                }
+                Step::Mount {
+                    src,
+                    dest,
+                    fstype,
+                    flags,
+                    data,
+                } => check(libc::mount(
+                    src.as_ref().map_or(std::ptr::null(), |x| x.as_ptr()),
+                    dest.as_ptr(),
+                    fstype.as_ref().map_or(std::ptr::null(), |x| x.as_ptr()),
+                    *flags,
+                    data.as_ref()
+                        .map_or(std::ptr::null(), |x| x.as_ptr() as *const libc::c_void),
+                )),
+                Step::Remount {
+                    path,
+                    flags,
+                    submount,
+                } => {
+                    let mut stat: libc::statvfs = std::mem::zeroed();
+                    match check(libc::statvfs(path.as_ptr(), &mut stat)) {
+                        Err(err) if *submount && err.raw_os_error() == Some(libc::EACCES) => {
+                            return Ok(())
+                        }
+                        result => result?,
+                    }
+
+                    let locked = LOCKED_FLAGS
+                        .iter()
+                        .filter(|(st, _)| stat.f_flag & st != 0)
+                        .fold(0, |acc, (_, ms)| acc | ms);
+
+                    check(libc::mount(
+                        std::ptr::null(),
+                        path.as_ptr(),
+                        std::ptr::null(),
+                        libc::MS_REMOUNT | libc::MS_BIND | locked | flags,
+                        std::ptr::null(),
+                    ))
+                }
+                Step::Dir { path, mode } => match check(libc::mkdir(path.as_ptr(), *mode)) {
+                    Err(err) if err.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+                    result => result,
+                },
+                Step::MountPoint { src, dest } => {
+                    let mut stat: libc::stat = std::mem::zeroed();
+                    check(libc::stat(src.as_ptr(), &mut stat))?;
+
+                    if stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                        return match check(libc::mkdir(dest.as_ptr(), 0o755)) {
+                            Err(err) if err.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+                            result => result,
+                        };
+                    }
+
+                    // Opening something which is already there for writing could fail on a read
+                    // only mount, even though it doesn't need to be created
+                    if libc::access(dest.as_ptr(), libc::F_OK) == 0 {
+                        return Ok(());
+                    }
+
+                    let fd = libc::open(
+                        dest.as_ptr(),
+                        libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC | libc::O_NOCTTY,
+                        0o644,
+                    );
+                    check(fd)?;
+                    libc::close(fd);
+                    Ok(())
+                }
+                Step::Write {
+                    path,
+                    content,
+                    mode,
+                } => {
+                    let fd = libc::open(
+                        path.as_ptr(),
+                        libc::O_CREAT | libc::O_TRUNC | libc::O_WRONLY | libc::O_CLOEXEC,
+                        *mode,
+                    );
+                    check(fd)?;
+                    let result = write_all(fd, content);
+                    libc::close(fd);
+                    result
+                }
+                Step::Symlink { target, link } => {
+                    check(libc::symlink(target.as_ptr(), link.as_ptr()))
+                }
+                Step::Chmod { path, mode } => check(libc::chmod(path.as_ptr(), *mode)),
+                Step::Chdir(path) => check(libc::chdir(path.as_ptr())),
+                Step::PivotRoot { new_root, put_old } => check(libc::syscall(
+                    libc::SYS_pivot_root,
+                    new_root.as_ptr(),
+                    put_old.as_ptr(),
+                ) as libc::c_int),
+                Step::Detach(path) => check(libc::umount2(path.as_ptr(), libc::MNT_DETACH)),
+                Step::DropCaps => {
+                    for cap in 0..64 {
+                        if prctl(libc::PR_CAPBSET_DROP, cap, 0) < 0 {
+                            // Past the last capability the kernel knows about
+                            match io::Error::last_os_error() {
+                                err if err.raw_os_error() == Some(libc::EINVAL) => break,
+                                err => return Err(err),
+                            }
+                        }
+                    }
+
+                    check(prctl(
+                        libc::PR_CAP_AMBIENT,
+                        libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong,
+                        0,
+                    ))
+                }
+                Step::Seccomp(filters) => {
+                    let program = libc::sock_fprog {
+                        len: filters.len() as libc::c_ushort,
+                        filter: filters.as_ptr() as *mut libc::sock_filter,
+                    };
+
+                    check(prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0))?;
+                    check(prctl(
+                        libc::PR_SET_SECCOMP,
+                        libc::SECCOMP_MODE_FILTER as libc::c_ulong,
+                        &program as *const libc::sock_fprog as libc::c_ulong,
+                    ))
+                }
+            }
+        }
+    }
+}
+
+/// Runs the steps, reporting the one which failed through the status pipe
+fn run(records: &[Record], status: RawFd) -> io::Result<()> {
+    for record in records {
+        if let Err(err) = record.step.run() {
+            write_record(status, &record.error, errno(&err), b"}}\n");
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// The start of the record reporting that `what` failed, the errno gets added once it happens
+fn error_record(what: &str) -> Vec<u8> {
+    let what = serde_json::to_string(what).unwrap();
+    format!("{{\"setup-error\":{{\"step\":{},\"errno\":", what).into_bytes()
+}
+
+/// Writes `prefix`, the number and `suffix` with a single write, so nothing can end up in between
+fn write_record(fd: RawFd, prefix: &[u8], number: i64, suffix: &[u8]) {
+    let mut buffer = [0; 20];
+    let digits = format_number(number, &mut buffer);
+
+    let iov = [prefix, digits, suffix].map(|x| libc::iovec {
+        iov_base: x.as_ptr() as *mut libc::c_void,
+        iov_len: x.len(),
+    });
+    unsafe {
+        libc::writev(fd, iov.as_ptr(), iov.len() as libc::c_int);
+    }
+}
+
+/// Formats the number without allocating
+fn format_number(number: i64, buffer: &mut [u8; 20]) -> &[u8] {
+    let mut rest = number.unsigned_abs();
+    let mut start = buffer.len();
+
+    loop {
+        start -= 1;
+        buffer[start] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+
+        if rest == 0 {
+            break;
+        }
+    }
+
+    if number < 0 {
+        start -= 1;
+        buffer[start] = b'-';
+    }
+
+    &buffer[start..]
+}
+
+fn close_other_fds(keep: RawFd) {
+    let ranges = [(3, keep - 1), (keep + 1, RawFd::MAX)];
+
+    for (first, last) in ranges.into_iter().filter(|(first, last)| first <= last) {
+        let closed =
+            unsafe { libc::syscall(libc::SYS_close_range, first as u32, last as u32, 0) == 0 };
+
+        // Kernels before 5.9 don't have close_range
+        if !closed {
+            (first..=last.min(1024)).for_each(|fd| unsafe {
+                libc::close(fd);
+            });
+        }
+    }
+}
+
+fn write_all(fd: RawFd, mut content: &[u8]) -> io::Result<()> {
+    while !content.is_empty() {
+        let written =
+            unsafe { libc::write(fd, content.as_ptr() as *const libc::c_void, content.len()) };
+
+        match written {
+            n if n >= 0 => content = &content[n as usize..],
+            _ if io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) => {}
+            _ => return Err(io::Error::last_os_error()),
+        }
+    }
+
+    Ok(())
+}
+
+/// The kernel reads every argument as an unsigned long, and checks the unused ones are 0, which
+/// plain integers passed through the varargs of `libc::prctl` aren't guaranteed to be
+unsafe fn prctl(option: libc::c_int, arg2: libc::c_ulong, arg3: libc::c_ulong) -> libc::c_int {
+    let unused: libc::c_ulong = 0;
+    libc::prctl(option, arg2, arg3, unused, unused)
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    match result {
+        result if result < 0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+fn errno(err: &io::Error) -> i64 {
+    err.raw_os_error().unwrap_or(0) as i64
+}
+
+/// Binds the target of symlinks like bwrap does, as links to absolute paths would point into the
+/// new root otherwise. Sources which can't be resolved are left for the mount to fail on.
+fn resolve(mount: BubMount) -> BubMount {
+    let real = |src: PathBuf| std::fs::canonicalize(&src).unwrap_or(src);
+
+    match mount {
+        BubMount::DevBind { src, dest } => BubMount::DevBind {
+            src: real(src),
+            dest,
+        },
+        BubMount::BindRO { src, dest } => BubMount::BindRO {
+            src: real(src),
+            dest,
+        },
+        BubMount::BindRW { src, dest } => BubMount::BindRW {
+            src: real(src),
+            dest,
+        },
+        mount => mount,
+    }
+}
+
+/// The mount points in `/proc/self/mountinfo`
+fn mount_points(mountinfo: &str) -> Vec<PathBuf> {
+    mountinfo
+        .lines()
+        .filter_map(|x| x.split(' ').nth(4))
+        .map(unescape)
+        .collect()
+}
+
+/// mountinfo writes spaces, tabs, newlines and backslashes as octal escapes
+fn unescape(path: &str) -> PathBuf {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        let octal = tail
+            .get(..3)
+            .filter(|x| byte == b'\\' && x.iter().all(|x| (b'0'..=b'7').contains(x)));
+
+        match octal {
+            Some(digits) => {
+                bytes.push(digits.iter().fold(0u8, |acc, x| (acc << 3) | (x - b'0')));
+                rest = &tail[3..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    PathBuf::from(OsStr::from_bytes(&bytes))
+}
+
+/// Where a path of the host is while the new root is put together
+fn host_path(path: &Path) -> PathBuf {
+    Path::new(OLD_ROOT).join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Where a path in the sandbox is while the new root is put together
+fn new_path(path: &Path) -> PathBuf {
+    Path::new(NEW_ROOT).join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Later sources go on top, like with bwrap, while overlayfs wants the topmost one first
+fn lower_dirs(sources: &[PathBuf]) -> String {
+    let lower = sources
+        .iter()
+        .rev()
+        .map(|x| escape(&host_path(x)))
+        .collect::<Vec<_>>()
+        .join(":");
+
+    format!("lowerdir={}", lower)
+}
+
+/// Overlayfs splits its options on `,` and the lower directories on `:`
+fn escape(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(':', "\\:")
+}
+
+fn cstring<P: AsRef<Path>>(path: P) -> Result<CString> {
+    Ok(CString::new(path.as_ref().as_os_str().as_bytes()).map_err(invalid)?)
+}
+
+fn invalid(err: std::ffi::NulError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the steps report when they fail
+    fn steps(records: &[Record]) -> Vec<String> {
+        records
+            .iter()
+            .map(|x| {
+                let record = std::str::from_utf8(&x.error).unwrap();
+                let what = record
+                    .strip_prefix("{\"setup-error\":{\"step\":")
+                    .and_then(|x| x.strip_suffix(",\"errno\":"))
+                    .unwrap();
+                serde_json::from_str(what).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn formats_numbers() {
+        let mut buffer = [0; 20];
+        assert_eq!(format_number(0, &mut buffer), b"0");
+        assert_eq!(format_number(137, &mut buffer), b"137");
+        assert_eq!(format_number(-2, &mut buffer), b"-2");
+        assert_eq!(
+            format_number(i64::MIN, &mut buffer),
+            i64::MIN.to_string().as_bytes()
+        );
+    }
+
+    #[test]
+    fn error_record_is_json() {
+        let mut record = error_record("binding \"a\" to /b");
+        record.extend_from_slice(b"2}}");
+
+        let value = serde_json::from_slice::<serde_json::Value>(&record).unwrap();
+        assert_eq!(value["setup-error"]["step"], "binding \"a\" to /b");
+        assert_eq!(value["setup-error"]["errno"], 2);
+    }
+
+    #[test]
+    fn escapes_overlay_options() {
+        assert_eq!(escape(Path::new("/a,b:c\\d")), "/a\\,b\\:c\\\\d");
+        assert_eq!(
+            lower_dirs(&["/usr".into(), "/opt/x:y".into()]),
+            "lowerdir=/oldroot/opt/x\\:y:/oldroot/usr"
+        );
+    }
+
+    #[test]
+    fn reads_mount_points() {
+        let mountinfo = "22 1 0:21 / / rw - ext4 /dev/sda rw\n\
+                         23 22 0:22 / /mnt/my\\040drive rw - tmpfs tmpfs rw\n\
+                         24 22 0:23 / /a\\134b rw - tmpfs tmpfs rw\n";
+
+        assert_eq!(
+            mount_points(mountinfo),
+            [
+                PathBuf::from("/"),
+                PathBuf::from("/mnt/my drive"),
+                PathBuf::from("/a\\b")
+            ]
+        );
+    }
+
+    #[test]
+    fn plans_mounts() {
+        let mut plan = Plan {
+            host_mounts: ["/", "/data", "/data/games/disk", "/database"]
+                .map(PathBuf::from)
+                .to_vec(),
+            ..Plan::default()
+        };
+
+        let mounts = [
+            BubMount::tmpfs("/tmp"),
+            BubMount::bind_rw("/data/games", "/games"),
+            BubMount::bind_ro("/usr", "/usr"),
+            BubMount::remount_ro("/games"),
+        ];
+        for (idx, mount) in mounts.into_iter().enumerate() {
+            plan.mount(idx, mount).unwrap();
+        }
+
+        assert_eq!(
+            steps(&plan.inner),
+            [
+                "creating /tmp",
+                "mounting a tmpfs on /tmp",
+                "binding /data/games to /games",
+                "binding /data/games to /games",
+                "remounting /games",
+                "remounting /games/disk",
+                "binding /usr to /usr",
+                "binding /usr to /usr",
+                "remounting read only /usr",
+                "remounting read only /games",
+                "remounting read only /games/disk",
+            ]
+        );
+        assert_eq!(
+            plan.mounts,
+            ["/tmp", "/games", "/games/disk", "/usr"].map(PathBuf::from)
+        );
+
+        // Submounts which can't be accessed are skipped
+        let submounts = plan
+            .inner
+            .iter()
+            .filter_map(|x| match &x.step {
+                Step::Remount { path, submount, .. } => Some((path.to_str().unwrap(), *submount)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            submounts,
+            [
+                ("/newroot/games", false),
+                ("/newroot/games/disk", true),
+                ("/newroot/usr", false),
+                ("/newroot/games", false),
+                ("/newroot/games/disk", true),
+            ]
+        );
+    }
+}
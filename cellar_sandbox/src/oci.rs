@@ -0,0 +1,690 @@
+use crate::seccomp::{SeccompAction, SeccompPolicy};
+use crate::{BubMount, Rlimit};
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// The version of the runtime spec the config follows, the first one with `defaultErrnoRet`
+pub const OCI_VERSION: &str = "1.1.0";
+
+/// Where the root of the container is, relative to the bundle
+const ROOTFS: &str = "rootfs";
+/// Where the content of data mounts goes, relative to the bundle
+const FILES: &str = "files";
+/// Where overlays without an upper directory of their own write to, relative to the bundle
+const OVERLAYS: &str = "overlays";
+
+/// The parts of an OCI runtime `config.json` which a sandbox can be described with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciSpec {
+    pub oci_version: String,
+    pub root: OciRoot,
+    pub process: OciProcess,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    pub mounts: Vec<OciMount>,
+    pub linux: OciLinux,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OciRoot {
+    pub path: PathBuf,
+    pub readonly: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciProcess {
+    pub terminal: bool,
+    pub user: OciUser,
+    pub args: Vec<String>,
+    pub env: Vec<String>,
+    pub cwd: PathBuf,
+    pub capabilities: OciCapabilities,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rlimits: Vec<OciRlimit>,
+    pub no_new_privileges: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OciUser {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// The capabilities the program keeps, the same ones end up in each of the sets
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OciCapabilities {
+    pub bounding: Vec<String>,
+    pub effective: Vec<String>,
+    pub permitted: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OciRlimit {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub hard: u64,
+    pub soft: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OciMount {
+    pub destination: PathBuf,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub source: PathBuf,
+    pub options: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciLinux {
+    pub namespaces: Vec<OciNamespace>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub uid_mappings: Vec<OciIdMapping>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gid_mappings: Vec<OciIdMapping>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seccomp: Option<OciSeccomp>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OciNamespace {
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Joins the namespace at the path instead of creating one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OciIdMapping {
+    #[serde(rename = "containerID")]
+    pub container_id: u32,
+    #[serde(rename = "hostID")]
+    pub host_id: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciSeccomp {
+    pub default_action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_errno_ret: Option<u16>,
+    pub architectures: Vec<String>,
+    pub syscalls: Vec<OciSyscall>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciSyscall {
+    pub names: Vec<String>,
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub errno_ret: Option<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<OciSyscallArg>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciSyscallArg {
+    pub index: u8,
+    pub value: u64,
+    pub value_two: u64,
+    pub op: String,
+}
+
+/// Something which has to be in the bundle next to the config
+#[derive(Debug, Clone)]
+enum BundleEntry {
+    Dir {
+        path: PathBuf,
+        mode: u32,
+    },
+    File {
+        path: PathBuf,
+        content: String,
+        mode: u32,
+    },
+    Symlink {
+        target: PathBuf,
+        path: PathBuf,
+    },
+}
+
+/// An OCI bundle which runs the same sandbox as a [`BubLauncher`](crate::BubLauncher), for
+/// runtimes like `crun` or `runc` or for tools which inspect containers.
+///
+/// bwrap puts the root together in an empty tmpfs, so the rootfs of the bundle starts out empty
+/// as well. The symlinks, directories and files bwrap would create there are created in the
+/// rootfs instead, and the content of data mounts is kept in the bundle.
+///
+/// Some options have no equivalent in the spec, these are listed in
+/// [`OciBundle::untranslated`] rather than failing.
+#[derive(Debug, Clone)]
+pub struct OciBundle {
+    path: PathBuf,
+    pub spec: OciSpec,
+    entries: Vec<BundleEntry>,
+    pub untranslated: Vec<String>,
+}
+
+impl OciBundle {
+    /// The directory the bundle is written to, which is where the runtime has to be pointed at
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the config and everything it refers to into the bundle directory
+    pub fn write(&self) -> io::Result<()> {
+        fs::create_dir_all(self.path.join(ROOTFS))?;
+
+        for entry in &self.entries {
+            match entry {
+                BundleEntry::Dir { path, mode } => {
+                    fs::create_dir_all(path)?;
+                    fs::set_permissions(path, fs::Permissions::from_mode(*mode))?;
+                }
+                BundleEntry::File {
+                    path,
+                    content,
+                    mode,
+                } => {
+                    create_parents(path)?;
+                    fs::write(path, content)?;
+                    fs::set_permissions(path, fs::Permissions::from_mode(*mode))?;
+                }
+                BundleEntry::Symlink { target, path } => {
+                    create_parents(path)?;
+                    // Writing the bundle again shouldn't fail on what the last time left behind
+                    if fs::symlink_metadata(path).is_ok() {
+                        fs::remove_file(path)?;
+                    }
+                    std::os::unix::fs::symlink(target, path)?;
+                }
+            }
+        }
+
+        let config = serde_json::to_string_pretty(&self.spec)?;
+        fs::write(self.path.join("config.json"), config)
+    }
+}
+
+/// What a [`BubLauncher`](crate::BubLauncher) gets exported with, see
+/// [`BubLauncher::oci_bundle`](crate::BubLauncher::oci_bundle)
+#[derive(Debug)]
+pub(crate) struct OciOptions {
+    pub mounts: Vec<BubMount>,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub inherit_env: bool,
+    pub unshare_user: bool,
+    pub unshare_ipc: bool,
+    pub unshare_pid: bool,
+    pub unshare_net: bool,
+    pub unshare_uts: bool,
+    pub unshare_cgroups: bool,
+    pub userns: Option<PathBuf>,
+    pub hostname: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub disable_userns: bool,
+    pub cap_add: Vec<String>,
+    pub rlimits: Vec<Rlimit>,
+    pub seccomp: Option<SeccompPolicy>,
+}
+
+pub(crate) fn bundle(path: PathBuf, options: OciOptions) -> OciBundle {
+    let mut bundle = OciBundle {
+        path,
+        spec: OciSpec {
+            oci_version: OCI_VERSION.to_string(),
+            root: OciRoot {
+                path: PathBuf::from(ROOTFS),
+                readonly: false,
+            },
+            process: process(&options),
+            hostname: options.hostname.clone(),
+            mounts: Vec::new(),
+            linux: linux(&options),
+        },
+        entries: Vec::new(),
+        untranslated: Vec::new(),
+    };
+
+    for (idx, mount) in options.mounts.into_iter().enumerate() {
+        bundle.mount(idx, mount);
+    }
+
+    if options.inherit_env {
+        bundle.untranslate("inheriting the environment of the host");
+    }
+    if options.disable_userns {
+        bundle.untranslate("disabling nested user namespaces");
+    }
+    if options.cap_add.iter().any(|x| x == "ALL") {
+        bundle.untranslate("keeping every capability");
+    }
+
+    bundle
+}
+
+impl OciBundle {
+    fn mount(&mut self, idx: usize, mount: BubMount) {
+        match mount {
+            BubMount::DevBind { src, dest } => self.bind(src, dest, &["nosuid"]),
+            BubMount::BindRO { src, dest } => self.bind(src, dest, &["nosuid", "nodev", "ro"]),
+            BubMount::BindRW { src, dest } => self.bind(src, dest, &["nosuid", "nodev"]),
+
+            BubMount::Symlink { src, dest } => {
+                if self.in_rootfs(&dest, "symlink") {
+                    self.entries.push(BundleEntry::Symlink {
+                        target: src,
+                        path: self.rootfs(&dest),
+                    });
+                }
+            }
+
+            BubMount::TmpFs { path, perms, size } => {
+                let mut options = vec![
+                    "nosuid".to_string(),
+                    "nodev".to_string(),
+                    format!("mode={:04o}", perms.unwrap_or(0o755)),
+                ];
+                if let Some(size) = size {
+                    options.push(format!("size={}", size));
+                }
+
+                self.push("tmpfs", "tmpfs", path, options);
+            }
+            BubMount::Proc { path } => self.push(
+                "proc",
+                "proc",
+                path,
+                vec!["nosuid".into(), "nodev".into(), "noexec".into()],
+            ),
+
+            BubMount::Dir { path, perms } => {
+                if self.in_rootfs(&path, "directory") {
+                    self.entries.push(BundleEntry::Dir {
+                        path: self.rootfs(&path),
+                        mode: perms.unwrap_or(0o755),
+                    });
+                }
+            }
+            BubMount::File {
+                content,
+                path,
+                perms,
+            } => {
+                if self.in_rootfs(&path, "file") {
+                    self.entries.push(BundleEntry::File {
+                        path: self.rootfs(&path),
+                        content,
+                        mode: perms.unwrap_or(0o666),
+                    });
+                }
+            }
+            BubMount::BindData {
+                content,
+                path,
+                perms,
+            } => {
+                let data = self.data(idx, content, perms);
+                self.bind(data, path, &["nosuid", "nodev"]);
+            }
+            BubMount::BindDataRO {
+                content,
+                path,
+                perms,
+            } => {
+                let data = self.data(idx, content, perms);
+                self.bind(data, path, &["nosuid", "nodev", "ro"]);
+            }
+
+            BubMount::Overlay {
+                sources,
+                upper,
+                work,
+                dest,
+            } => {
+                let options = vec![
+                    lower_dirs(&sources),
+                    format!("upperdir={}", escape(&upper)),
+                    format!("workdir={}", escape(&work)),
+                ];
+                self.push("overlay", "overlay", dest, options);
+            }
+            BubMount::TmpOverlay { sources, dest } => {
+                let base = self.path.join(OVERLAYS).join(format!("overlay-{}", idx));
+                let (upper, work) = (base.join("upper"), base.join("work"));
+                for dir in [&upper, &work] {
+                    self.entries.push(BundleEntry::Dir {
+                        path: dir.clone(),
+                        mode: 0o755,
+                    });
+                }
+
+                self.untranslated.push(format!(
+                    "tmp overlay on {}, writes are kept in the bundle between runs",
+                    dest.display()
+                ));
+
+                let options = vec![
+                    lower_dirs(&sources),
+                    format!("upperdir={}", escape(&upper)),
+                    format!("workdir={}", escape(&work)),
+                ];
+                self.push("overlay", "overlay", dest, options);
+            }
+            // Overlayfs wants at least two lower directories when there's no upper one
+            BubMount::RoOverlay { mut sources, dest } if sources.len() == 1 => {
+                self.bind(sources.remove(0), dest, &["nosuid", "nodev", "ro"])
+            }
+            BubMount::RoOverlay { sources, dest } => {
+                self.push("overlay", "overlay", dest, vec![lower_dirs(&sources)])
+            }
+
+            BubMount::Chmod { mode, path } => {
+                let rootfs = self.rootfs(&path);
+                let created = self.entries.iter_mut().rev().find_map(|x| match x {
+                    BundleEntry::Dir { path, mode } | BundleEntry::File { path, mode, .. }
+                        if *path == rootfs =>
+                    {
+                        Some(mode)
+                    }
+                    _ => None,
+                });
+
+                match created {
+                    Some(created) => *created = mode,
+                    None => self.untranslated.push(format!(
+                        "chmod of {}, which isn't created in the rootfs",
+                        path.display()
+                    )),
+                }
+            }
+            BubMount::RemountRO { path } => {
+                let mut found = false;
+                for mount in &mut self.spec.mounts {
+                    if mount.destination.starts_with(&path) {
+                        if !mount.options.iter().any(|x| x == "ro") {
+                            mount.options.push("ro".to_string());
+                        }
+                        found = true;
+                    }
+                }
+
+                if !found {
+                    self.untranslated.push(format!(
+                        "read only remount of {}, which isn't a mount",
+                        path.display()
+                    ));
+                }
+            }
+        }
+    }
+
+    fn bind(&mut self, src: PathBuf, dest: PathBuf, options: &[&str]) {
+        let mut options = options.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        options.insert(0, "rbind".to_string());
+
+        self.spec.mounts.push(OciMount {
+            destination: dest,
+            kind: "bind".to_string(),
+            source: src,
+            options,
+        });
+    }
+
+    fn push(&mut self, kind: &str, source: &str, dest: PathBuf, options: Vec<String>) {
+        self.spec.mounts.push(OciMount {
+            destination: dest,
+            kind: kind.to_string(),
+            source: PathBuf::from(source),
+            options,
+        });
+    }
+
+    /// Writes the content of a data mount into the bundle, giving back where it ends up
+    fn data(&mut self, idx: usize, content: String, perms: Option<u32>) -> PathBuf {
+        let path = self.path.join(FILES).join(format!("data-{}", idx));
+        self.entries.push(BundleEntry::File {
+            path: path.clone(),
+            content,
+            mode: perms.unwrap_or(0o600),
+        });
+
+        path
+    }
+
+    /// The rootfs is there before any of the mounts, so anything created beneath a mount would
+    /// be hidden by it
+    fn in_rootfs(&mut self, path: &Path, what: &str) -> bool {
+        match self
+            .spec
+            .mounts
+            .iter()
+            .find(|x| path.starts_with(&x.destination))
+        {
+            Some(mount) => {
+                self.untranslated.push(format!(
+                    "{} {}, which is beneath the mount on {}",
+                    what,
+                    path.display(),
+                    mount.destination.display()
+                ));
+                false
+            }
+            None => true,
+        }
+    }
+
+    fn rootfs(&self, path: &Path) -> PathBuf {
+        self.path
+            .join(ROOTFS)
+            .join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    fn untranslate(&mut self, what: &str) {
+        self.untranslated.push(what.to_string());
+    }
+}
+
+fn process(options: &OciOptions) -> OciProcess {
+    // bwrap keeps the ids of the user unless told otherwise
+    let user = OciUser {
+        uid: options.uid.unwrap_or_else(|| unsafe { libc::getuid() }),
+        gid: options.gid.unwrap_or_else(|| unsafe { libc::getgid() }),
+    };
+
+    // Without privileges bwrap drops every capability, other than the ones which get added back
+    let caps = options
+        .cap_add
+        .iter()
+        .filter(|x| *x != "ALL")
+        .map(|x| match x.to_ascii_uppercase() {
+            cap if cap.starts_with("CAP_") => cap,
+            cap => format!("CAP_{}", cap),
+        })
+        .collect::<Vec<_>>();
+
+    OciProcess {
+        terminal: false,
+        user,
+        args: options.args.clone(),
+        env: options
+            .env
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect(),
+        cwd: PathBuf::from("/"),
+        capabilities: OciCapabilities {
+            bounding: caps.clone(),
+            effective: caps.clone(),
+            permitted: caps,
+        },
+        rlimits: options
+            .rlimits
+            .iter()
+            .map(|x| OciRlimit {
+                kind: x.name().to_string(),
+                hard: x.value(),
+                soft: x.value(),
+            })
+            .collect(),
+        // bwrap always sets it, seccomp filters need it without privileges anyway
+        no_new_privileges: true,
+    }
+}
+
+fn linux(options: &OciOptions) -> OciLinux {
+    let mut namespaces = vec![namespace("mount")];
+    let unshared = [
+        (options.unshare_pid, "pid"),
+        (options.unshare_net, "network"),
+        (options.unshare_ipc, "ipc"),
+        (options.unshare_uts, "uts"),
+        (options.unshare_cgroups, "cgroup"),
+    ];
+    namespaces.extend(
+        unshared
+            .iter()
+            .filter(|x| x.0)
+            .map(|(_, kind)| namespace(kind)),
+    );
+
+    let mut linux = OciLinux {
+        namespaces,
+        uid_mappings: Vec::new(),
+        gid_mappings: Vec::new(),
+        seccomp: options.seccomp.as_ref().map(seccomp),
+    };
+
+    match &options.userns {
+        Some(path) => linux.namespaces.push(OciNamespace {
+            kind: "user".to_string(),
+            path: Some(path.clone()),
+        }),
+        None if options.unshare_user => {
+            linux.namespaces.push(namespace("user"));
+
+            // Like bwrap, only the user itself is mapped, which works without privileges
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            linux.uid_mappings.push(OciIdMapping {
+                container_id: options.uid.unwrap_or(uid),
+                host_id: uid,
+                size: 1,
+            });
+            linux.gid_mappings.push(OciIdMapping {
+                container_id: options.gid.unwrap_or(gid),
+                host_id: gid,
+                size: 1,
+            });
+        }
+        None => {}
+    }
+
+    linux
+}
+
+fn namespace(kind: &str) -> OciNamespace {
+    OciNamespace {
+        kind: kind.to_string(),
+        path: None,
+    }
+}
+
+fn seccomp(policy: &SeccompPolicy) -> OciSeccomp {
+    let (default_action, default_errno_ret) = action(policy.default_action);
+
+    OciSeccomp {
+        default_action,
+        default_errno_ret,
+        architectures: architectures(),
+        syscalls: policy
+            .rules
+            .iter()
+            .map(|rule| {
+                let (action, errno_ret) = action(rule.action);
+
+                OciSyscall {
+                    names: vec![rule.syscall.clone()],
+                    action,
+                    errno_ret,
+                    // The compiled filter only compares the lower 32 bits, this does the same
+                    args: rule
+                        .arg
+                        .iter()
+                        .map(|arg| OciSyscallArg {
+                            index: arg.index,
+                            value: 0xffff_ffff,
+                            value_two: arg.value & 0xffff_ffff,
+                            op: "SCMP_CMP_MASKED_EQ".to_string(),
+                        })
+                        .collect(),
+                }
+            })
+            .collect(),
+    }
+}
+
+fn action(action: SeccompAction) -> (String, Option<u16>) {
+    let (name, errno) = match action {
+        SeccompAction::Allow => ("SCMP_ACT_ALLOW", None),
+        SeccompAction::Errno(errno) => ("SCMP_ACT_ERRNO", Some(errno)),
+        SeccompAction::Trap => ("SCMP_ACT_TRAP", None),
+        SeccompAction::Log => ("SCMP_ACT_LOG", None),
+        SeccompAction::KillProcess => ("SCMP_ACT_KILL_PROCESS", None),
+    };
+
+    (name.to_string(), errno)
+}
+
+/// The same arches the compiled filter knows about
+fn architectures() -> Vec<String> {
+    let arches: &[&str] = if cfg!(target_arch = "x86_64") {
+        &["SCMP_ARCH_X86_64", "SCMP_ARCH_X86"]
+    } else if cfg!(target_arch = "aarch64") {
+        &["SCMP_ARCH_AARCH64"]
+    } else {
+        &[]
+    };
+
+    arches.iter().map(|x| x.to_string()).collect()
+}
+
+/// Later sources go on top, like with bwrap, while overlayfs wants the topmost one first
+fn lower_dirs(sources: &[PathBuf]) -> String {
+    let lower = sources
+        .iter()
+        .rev()
+        .map(|x| escape(x))
+        .collect::<Vec<_>>()
+        .join(":");
+
+    format!("lowerdir={}", lower)
+}
+
+/// Overlayfs splits its options on `,` and the lower directories on `:`
+fn escape(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(':', "\\:")
+}
+
+fn create_parents(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,46 @@
+use crate::{BubLauncher, FirejailLauncher, Result};
+
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::process::Command;
+
+/// Quotes a word so a POSIX shell reads it back unchanged
+pub fn quote(word: &OsStr) -> Cow<'_, str> {
+    let word = word.to_string_lossy();
+
+    let safe = |x: char| x.is_ascii_alphanumeric() || "%+,-./:=@_".contains(x);
+    if !word.is_empty() && word.chars().all(safe) {
+        return word;
+    }
+
+    Cow::Owned(format!("'{}'", word.replace('\'', r#"'\''"#)))
+}
+
+/// Renders the command as it could be typed into a shell, with any env vars set on it in front.
+///
+/// Descriptors handed over with [`pass_fds`](crate::fd::pass_fds) only show up as their number.
+pub fn render(cmd: &Command) -> String {
+    let env = cmd
+        .get_envs()
+        .filter_map(|(key, value)| value.map(|value| format!("{}={}", quote(key), quote(value))));
+
+    let args = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|x| quote(x).into_owned());
+
+    env.chain(args).collect::<Vec<_>>().join(" ")
+}
+
+impl BubLauncher {
+    /// Renders the bwrap invocation, the arguments are always passed directly so they're readable
+    pub fn render(mut self) -> Result<String> {
+        self.args_fd(false);
+        Ok(render(&self.command()?))
+    }
+}
+
+impl FirejailLauncher {
+    pub fn render(self) -> Result<String> {
+        Ok(render(&self.command()?))
+    }
+}
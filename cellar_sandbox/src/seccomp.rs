@@ -0,0 +1,487 @@
+use crate::{Result, SandboxError};
+
+use serde::{Deserialize, Serialize};
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+const AUDIT_ARCH_I386: u32 = 0x4000_0003;
+const AUDIT_ARCH_AARCH64: u32 = 0xc000_00b7;
+
+/// Syscalls of the x32 ABI share the x86_64 arch, but have this bit set in their number
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// Offsets into `struct seccomp_data`
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+const DATA_ARGS: u32 = 16;
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JA: u16 = 0x05;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+
+/// The kernel refuses to load programs longer than this
+const BPF_MAXINSNS: usize = 4096;
+
+const PTRACE_ATTACH: u64 = 16;
+const PTRACE_SEIZE: u64 = 0x4206;
+const TIOCSTI: u64 = 0x5412;
+const TIOCLINUX: u64 = 0x541c;
+
+macro_rules! syscalls {
+    ($($name:ident = $i386:expr),* $(,)?) => {
+        /// Syscalls which can be referred to by name, with their number on the native arch and on
+        /// the 32 bit compat arch wine uses to run 32 bit programs.
+        const SYSCALLS: &[(&str, libc::c_long, Option<u32>)] = &[
+            $((stringify!($name), libc::$name, $i386)),*
+        ];
+    };
+}
+
+syscalls! {
+    SYS_acct = Some(51),
+    SYS_add_key = Some(286),
+    SYS_adjtimex = Some(124),
+    SYS_bpf = Some(357),
+    SYS_chroot = Some(61),
+    SYS_clock_settime = Some(264),
+    SYS_clone3 = Some(435),
+    SYS_delete_module = Some(129),
+    SYS_finit_module = Some(350),
+    SYS_fsconfig = Some(431),
+    SYS_fsmount = Some(432),
+    SYS_fsopen = Some(430),
+    SYS_fspick = Some(433),
+    SYS_init_module = Some(128),
+    SYS_ioctl = Some(54),
+    SYS_kexec_file_load = None,
+    SYS_kexec_load = Some(283),
+    SYS_keyctl = Some(288),
+    SYS_mount = Some(21),
+    SYS_move_mount = Some(429),
+    SYS_name_to_handle_at = Some(341),
+    SYS_open_by_handle_at = Some(342),
+    SYS_open_tree = Some(428),
+    SYS_perf_event_open = Some(336),
+    SYS_personality = Some(136),
+    SYS_pidfd_open = Some(434),
+    SYS_pivot_root = Some(217),
+    SYS_process_vm_readv = Some(347),
+    SYS_process_vm_writev = Some(348),
+    SYS_ptrace = Some(26),
+    SYS_quotactl = Some(131),
+    SYS_reboot = Some(88),
+    SYS_request_key = Some(287),
+    SYS_setns = Some(346),
+    SYS_settimeofday = Some(79),
+    SYS_swapoff = Some(115),
+    SYS_swapon = Some(87),
+    SYS_syslog = Some(103),
+    SYS_umount2 = Some(52),
+    SYS_unshare = Some(310),
+    SYS_userfaultfd = Some(374),
+    SYS_vhangup = Some(111),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SeccompAction {
+    Allow,
+    /// Fails the syscall with the given errno
+    Errno(u16),
+    /// Sends `SIGSYS` to the calling thread
+    Trap,
+    /// Allows the syscall, but logs it through the audit log
+    Log,
+    KillProcess,
+}
+
+impl SeccompAction {
+    const fn ret(&self) -> u32 {
+        match self {
+            Self::Allow => SECCOMP_RET_ALLOW,
+            Self::Errno(errno) => SECCOMP_RET_ERRNO | *errno as u32,
+            Self::Trap => SECCOMP_RET_TRAP,
+            Self::Log => SECCOMP_RET_LOG,
+            Self::KillProcess => SECCOMP_RET_KILL_PROCESS,
+        }
+    }
+}
+
+/// Matches one of the arguments of a syscall against a value.
+///
+/// Only the lower 32 bits are compared, which is what the kernel looks at for the `int` sized
+/// arguments this is meant for, like ioctl requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeccompArg {
+    pub index: u8,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeccompRule {
+    pub syscall: String,
+    pub action: SeccompAction,
+
+    #[serde(default)]
+    pub arg: Option<SeccompArg>,
+}
+
+/// A declarative syscall filter which gets compiled into a classic BPF program for bwrap.
+///
+/// Rules are checked in order and the first one to match decides what happens, any syscall which
+/// doesn't match a rule gets the default action.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeccompPolicy {
+    pub default_action: SeccompAction,
+    pub rules: Vec<SeccompRule>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+const fn stmt(code: u16, k: u32) -> SockFilter {
+    SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+const fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter { code, jt, jf, k }
+}
+
+impl SeccompPolicy {
+    /// Allows everything except for the syscalls which get denied
+    pub fn denylist() -> SeccompPolicy {
+        SeccompPolicy {
+            default_action: SeccompAction::Allow,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Denies everything except for the syscalls which get allowed
+    pub fn allowlist() -> SeccompPolicy {
+        SeccompPolicy {
+            default_action: SeccompAction::Errno(libc::EPERM as u16),
+            rules: Vec::new(),
+        }
+    }
+
+    /// Denies syscalls which wine has no use for, but which can be used to escape or attack the
+    /// host, such as pushing input into the terminal with `TIOCSTI`
+    pub fn wine() -> SeccompPolicy {
+        let mut policy = SeccompPolicy::denylist();
+
+        [
+            "keyctl",
+            "add_key",
+            "request_key",
+            "kexec_load",
+            "kexec_file_load",
+            "init_module",
+            "finit_module",
+            "delete_module",
+            "reboot",
+            "swapon",
+            "swapoff",
+            "acct",
+            "quotactl",
+            "syslog",
+            "bpf",
+            "perf_event_open",
+            "userfaultfd",
+            "open_by_handle_at",
+            "name_to_handle_at",
+            "vhangup",
+            "settimeofday",
+            "clock_settime",
+            "adjtimex",
+            "mount",
+            "umount2",
+            "pivot_root",
+            "chroot",
+            "setns",
+            "fsopen",
+            "fsconfig",
+            "fsmount",
+            "fspick",
+            "open_tree",
+            "move_mount",
+        ]
+        .iter()
+        .for_each(|x| {
+            policy.deny(*x);
+        });
+
+        // Tracing the children of a process is still fine, attaching to anything else is not
+        policy
+            .deny_arg("ptrace", 0, PTRACE_ATTACH)
+            .deny_arg("ptrace", 0, PTRACE_SEIZE)
+            .deny_arg("ioctl", 1, TIOCSTI)
+            .deny_arg("ioctl", 1, TIOCLINUX);
+
+        policy
+    }
+
+    pub fn rule(&mut self, rule: SeccompRule) -> &mut SeccompPolicy {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn deny<T: Into<String>>(&mut self, syscall: T) -> &mut SeccompPolicy {
+        self.rule(SeccompRule {
+            syscall: syscall.into(),
+            action: SeccompAction::Errno(libc::EPERM as u16),
+            arg: None,
+        })
+    }
+
+    /// Denies the syscall only when the argument at `index` is `value`
+    pub fn deny_arg<T: Into<String>>(
+        &mut self,
+        syscall: T,
+        index: u8,
+        value: u64,
+    ) -> &mut SeccompPolicy {
+        self.rule(SeccompRule {
+            syscall: syscall.into(),
+            action: SeccompAction::Errno(libc::EPERM as u16),
+            arg: Some(SeccompArg { index, value }),
+        })
+    }
+
+    pub fn allow<T: Into<String>>(&mut self, syscall: T) -> &mut SeccompPolicy {
+        self.rule(SeccompRule {
+            syscall: syscall.into(),
+            action: SeccompAction::Allow,
+            arg: None,
+        })
+    }
+
+    /// Compiles the policy into the raw `struct sock_filter` array bwrap reads from `--seccomp`
+    pub fn compile(&self) -> Result<Vec<u8>> {
+        let mut program = vec![stmt(BPF_LD_W_ABS, DATA_ARCH)];
+
+        for (arch, native) in Self::arches() {
+            let block = self.compile_arch(arch, native)?;
+
+            program.push(jump(BPF_JMP_JEQ_K, arch, 1, 0));
+            program.push(stmt(BPF_JMP_JA, block.len() as u32));
+            program.extend(block);
+        }
+
+        // The syscall numbers can't be trusted for an arch we don't know about
+        program.push(stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS));
+
+        if program.len() > BPF_MAXINSNS {
+            return Err(SandboxError::SeccompTooLarge(program.len()));
+        }
+
+        Ok(program
+            .into_iter()
+            .flat_map(|x| {
+                let mut raw = Vec::with_capacity(8);
+                raw.extend_from_slice(&x.code.to_ne_bytes());
+                raw.push(x.jt);
+                raw.push(x.jf);
+                raw.extend_from_slice(&x.k.to_ne_bytes());
+                raw
+            })
+            .collect())
+    }
+
+    /// The arches syscalls can come from, and whether they are the native one
+    fn arches() -> Vec<(u32, bool)> {
+        if cfg!(target_arch = "x86_64") {
+            vec![(AUDIT_ARCH_X86_64, true), (AUDIT_ARCH_I386, false)]
+        } else if cfg!(target_arch = "aarch64") {
+            vec![(AUDIT_ARCH_AARCH64, true)]
+        } else {
+            vec![]
+        }
+    }
+
+    fn compile_arch(&self, arch: u32, native: bool) -> Result<Vec<SockFilter>> {
+        let mut block = vec![stmt(BPF_LD_W_ABS, DATA_NR)];
+
+        if arch == AUDIT_ARCH_X86_64 {
+            block.push(jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1));
+            block.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+        }
+
+        for rule in &self.rules {
+            let nr = match syscall_number(&rule.syscall, native)? {
+                Some(nr) => nr,
+                // The syscall doesn't exist on this arch, so there is nothing to filter
+                None => continue,
+            };
+
+            match rule.arg {
+                None => {
+                    block.push(jump(BPF_JMP_JEQ_K, nr, 0, 1));
+                    block.push(stmt(BPF_RET_K, rule.action.ret()));
+                }
+                Some(arg) => {
+                    if arg.index > 5 {
+                        return Err(SandboxError::SeccompArgument(arg.index));
+                    }
+
+                    block.push(jump(BPF_JMP_JEQ_K, nr, 0, 4));
+                    block.push(stmt(BPF_LD_W_ABS, DATA_ARGS + 8 * arg.index as u32));
+                    block.push(jump(BPF_JMP_JEQ_K, arg.value as u32, 0, 1));
+                    block.push(stmt(BPF_RET_K, rule.action.ret()));
+                    block.push(stmt(BPF_LD_W_ABS, DATA_NR));
+                }
+            }
+        }
+
+        block.push(stmt(BPF_RET_K, self.default_action.ret()));
+        Ok(block)
+    }
+}
+
+/// Looks up the number of a syscall, which may not exist on the compat arch
+fn syscall_number(name: &str, native: bool) -> Result<Option<u32>> {
+    let (_, nr, compat) = SYSCALLS
+        .iter()
+        .find(|x| x.0.strip_prefix("SYS_") == Some(name))
+        .ok_or_else(|| SandboxError::UnknownSyscall(name.to_string()))?;
+
+    match native {
+        true => Ok(Some(*nr as u32)),
+        false => Ok(*compat),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(program: &[u8]) -> Vec<SockFilter> {
+        program
+            .chunks_exact(8)
+            .map(|x| SockFilter {
+                code: u16::from_ne_bytes([x[0], x[1]]),
+                jt: x[2],
+                jf: x[3],
+                k: u32::from_ne_bytes([x[4], x[5], x[6], x[7]]),
+            })
+            .collect()
+    }
+
+    /// Runs the program the way the kernel would for a syscall, giving back what it returns
+    fn run(program: &[SockFilter], arch: u32, nr: u32, args: [u64; 6]) -> u32 {
+        let mut data = Vec::new();
+        data.extend_from_slice(&nr.to_ne_bytes());
+        data.extend_from_slice(&arch.to_ne_bytes());
+        data.extend_from_slice(&0u64.to_ne_bytes());
+        args.iter()
+            .for_each(|x| data.extend_from_slice(&x.to_ne_bytes()));
+
+        let (mut pc, mut acc) = (0, 0);
+        loop {
+            let insn = program[pc];
+            pc += 1;
+
+            match insn.code {
+                BPF_LD_W_ABS => {
+                    let offset = insn.k as usize;
+                    acc = u32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap());
+                }
+                BPF_JMP_JA => pc += insn.k as usize,
+                BPF_JMP_JEQ_K | BPF_JMP_JGE_K => {
+                    let taken = match insn.code {
+                        BPF_JMP_JEQ_K => acc == insn.k,
+                        _ => acc >= insn.k,
+                    };
+                    pc += match taken {
+                        true => insn.jt,
+                        false => insn.jf,
+                    } as usize;
+                }
+                BPF_RET_K => return insn.k,
+                code => panic!("unexpected instruction {:#x}", code),
+            }
+        }
+    }
+
+    fn policy() -> SeccompPolicy {
+        let mut policy = SeccompPolicy::denylist();
+        policy.deny("ptrace").deny_arg("ioctl", 1, TIOCSTI);
+        policy
+    }
+
+    #[test]
+    fn jumps_stay_in_program() {
+        let program = decode(&policy().compile().unwrap());
+
+        // The load of the arch, a check and a jump for every arch and the kill for unknown ones
+        // around the blocks. A block loads the number, takes 2 instructions for ptrace, 5 for
+        // ioctl with its argument and 1 for the default, x86_64 rejects x32 syscalls with 2 more.
+        let expected = match SeccompPolicy::arches().len() {
+            2 => 1 + (2 + 11) + (2 + 9) + 1,
+            1 => 1 + (2 + 9) + 1,
+            _ => 2,
+        };
+        assert_eq!(program.len(), expected);
+
+        for (pc, insn) in program.iter().enumerate() {
+            let furthest = match insn.code {
+                BPF_JMP_JA => insn.k as usize,
+                BPF_JMP_JEQ_K | BPF_JMP_JGE_K => insn.jt.max(insn.jf) as usize,
+                _ => continue,
+            };
+            assert!(pc + 1 + furthest < program.len(), "jump at {} leaves", pc);
+        }
+        assert_eq!(program.last().unwrap().code, BPF_RET_K);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn filters_syscalls() {
+        let program = decode(&policy().compile().unwrap());
+        let eperm = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        let x86_64 = |nr: libc::c_long, args| run(&program, AUDIT_ARCH_X86_64, nr as u32, args);
+
+        assert_eq!(x86_64(libc::SYS_ptrace, [0; 6]), eperm);
+        assert_eq!(x86_64(libc::SYS_ioctl, [0, TIOCSTI, 0, 0, 0, 0]), eperm);
+        assert_eq!(
+            x86_64(libc::SYS_ioctl, [0, TIOCLINUX, 0, 0, 0, 0]),
+            SECCOMP_RET_ALLOW
+        );
+        assert_eq!(x86_64(libc::SYS_getpid, [0; 6]), SECCOMP_RET_ALLOW);
+        assert_eq!(
+            run(&program, AUDIT_ARCH_X86_64, X32_SYSCALL_BIT | 39, [0; 6]),
+            SECCOMP_RET_ERRNO | libc::ENOSYS as u32
+        );
+
+        // The 32 bit numbers are different, ptrace is 26 and ioctl 54 there
+        assert_eq!(run(&program, AUDIT_ARCH_I386, 26, [0; 6]), eperm);
+        assert_eq!(
+            run(&program, AUDIT_ARCH_I386, 54, [0, TIOCSTI, 0, 0, 0, 0]),
+            eperm
+        );
+        assert_eq!(
+            run(&program, AUDIT_ARCH_I386, 101, [0; 6]),
+            SECCOMP_RET_ALLOW
+        );
+
+        assert_eq!(
+            run(&program, AUDIT_ARCH_AARCH64, 117, [0; 6]),
+            SECCOMP_RET_KILL_PROCESS
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use crate::{Result, SandboxError};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::de::IoRead;
+use serde_json::{StreamDeserializer, Value};
+
+/// What bwrap reports once the sandbox is set up, namespaces are only there if they were unshared
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SandboxInfo {
+    pub child_pid: i32,
+
+    #[serde(default)]
+    pub cgroup_namespace: Option<u64>,
+    #[serde(default)]
+    pub ipc_namespace: Option<u64>,
+    #[serde(default)]
+    pub mnt_namespace: Option<u64>,
+    #[serde(default)]
+    pub net_namespace: Option<u64>,
+    #[serde(default)]
+    pub pid_namespace: Option<u64>,
+    #[serde(default)]
+    pub uts_namespace: Option<u64>,
+    #[serde(default)]
+    pub user_namespace: Option<u64>,
+}
+
+impl SandboxInfo {
+    /// A sandbox which is only a process, without any namespaces of its own
+    pub fn process(pid: i32) -> SandboxInfo {
+        SandboxInfo {
+            child_pid: pid,
+            cgroup_namespace: None,
+            ipc_namespace: None,
+            mnt_namespace: None,
+            net_namespace: None,
+            pid_namespace: None,
+            uts_namespace: None,
+            user_namespace: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ExitInfo {
+    exit_code: i32,
+}
+
+/// Only the native sandbox reports what went wrong this way, bwrap puts it on stderr
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct SetupInfo {
+    setup_error: SetupError,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetupError {
+    step: String,
+    errno: i32,
+}
+
+/// The read end of the pipe bwrap writes its status to.
+///
+/// `--json-status-fd` writes an object per line, while `--info-fd` spreads its one object over
+/// several lines, so this reads whole JSON values rather than lines.
+pub struct SandboxStatus(StreamDeserializer<'static, IoRead<BufReader<File>>, Value>);
+
+impl std::fmt::Debug for SandboxStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SandboxStatus").finish()
+    }
+}
+
+impl SandboxStatus {
+    /// Creates the pipe, returning the end which is handed to bwrap along with it
+    pub(crate) fn pipe() -> io::Result<(SandboxStatus, File)> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        Ok((SandboxStatus::new(read), write))
+    }
+
+    fn new(read: File) -> SandboxStatus {
+        SandboxStatus(serde_json::Deserializer::from_reader(BufReader::new(read)).into_iter())
+    }
+
+    /// Reads the next status object, `None` once bwrap has closed the pipe
+    fn next<T: for<'de> Deserialize<'de>>(&mut self) -> Result<Option<T>> {
+        for value in &mut self.0 {
+            let value = value.map_err(io::Error::from)?;
+
+            // Skip over anything we don't know how to read, newer versions may report more
+            if let Ok(value) = serde_json::from_value(value) {
+                return Ok(Some(value));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// A running sandbox, along with what bwrap told us about it
+#[derive(Debug)]
+pub struct SandboxHandle {
+    pub child: Child,
+    pub info: SandboxInfo,
+    status: SandboxStatus,
+}
+
+impl SandboxHandle {
+    /// Spawns the command from [`BubLauncher::command_with_status`](crate::BubLauncher) and waits
+    /// until the sandbox is set up
+    pub fn spawn(mut cmd: Command, mut status: SandboxStatus) -> Result<SandboxHandle> {
+        let child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                drop(cmd);
+
+                return Err(match status.next()? {
+                    Some(SetupInfo {
+                        setup_error: SetupError { step, errno },
+                    }) => SandboxError::Setup {
+                        step,
+                        source: io::Error::from_raw_os_error(errno),
+                    },
+                    None => err.into(),
+                });
+            }
+        };
+
+        // The command holds on to the write end of the pipe, which would keep us from ever seeing
+        // the end of it
+        drop(cmd);
+
+        let info = status.next()?.ok_or(SandboxError::StatusMissing)?;
+
+        Ok(SandboxHandle {
+            child,
+            info,
+            status,
+        })
+    }
+
+    /// Waits for the sandbox to exit, returning the exit code of the sandboxed program
+    pub fn wait(mut self) -> Result<i32> {
+        let exit = self.child.wait()?;
+
+        // Only --json-status-fd reports this, --info-fd just gets closed
+        if let Some(ExitInfo { exit_code }) = self.status.next()? {
+            return Ok(exit_code);
+        }
+
+        Ok(exit
+            .code()
+            .unwrap_or_else(|| 128 + exit.signal().unwrap_or(0)))
+    }
+}
+
+/// A process inside of a sandbox
+#[derive(Debug, Clone)]
+pub struct Process {
+    pub pid: i32,
+    pub cmdline: String,
+}
+
+/// Finds the process and everything started by it, by walking through `/proc`
+pub fn processes(root: i32) -> Result<Vec<Process>> {
+    let mut children = HashMap::<i32, Vec<i32>>::new();
+
+    for entry in std::fs::read_dir("/proc")? {
+        let pid = match entry?
+            .file_name()
+            .to_str()
+            .and_then(|x| x.parse::<i32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+
+        // The process might be gone by now, which is fine
+        if let Some(ppid) = parent_pid(pid) {
+            children.entry(ppid).or_default().push(pid);
+        }
+    }
+
+    let mut found = Vec::new();
+    let mut queue = vec![root];
+    while let Some(pid) = queue.pop() {
+        let cmdline = match std::fs::read(format!("/proc/{}/cmdline", pid)) {
+            Ok(cmdline) => String::from_utf8_lossy(&cmdline)
+                .trim_end_matches('\0')
+                .replace('\0', " "),
+            Err(_) => continue,
+        };
+
+        found.push(Process { pid, cmdline });
+        queue.extend(children.get(&pid).into_iter().flatten());
+    }
+
+    found.sort_by_key(|x| x.pid);
+    Ok(found)
+}
+
+/// How long [`kill`] waits for the sandbox to be gone
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Kills the process and everything started by it, and waits until it is gone.
+///
+/// This has to be `SIGKILL`, as the kernel drops any other signal from outside of a pid namespace
+/// which its init has no handler for. Killing the init of a sandbox takes everything in it along,
+/// the rest of the processes only matter for sandboxes without a pid namespace of their own.
+pub fn kill(pid: i32) -> Result<()> {
+    for process in processes(pid)? {
+        // Processes can be gone by now, which is what we want anyway
+        unsafe {
+            libc::kill(process.pid, libc::SIGKILL);
+        }
+    }
+
+    let start = Instant::now();
+    while is_alive(pid) {
+        if start.elapsed() > KILL_TIMEOUT {
+            return Err(SandboxError::StillRunning(pid));
+        }
+
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    Ok(())
+}
+
+/// Zombies count as gone, they only wait for their parent to notice
+pub fn is_alive(pid: i32) -> bool {
+    let stat = match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat,
+        Err(_) => return false,
+    };
+
+    let state = stat
+        .rfind(')')
+        .and_then(|x| stat[x + 1..].split_whitespace().next());
+    !matches!(state, Some("Z") | Some("X") | None)
+}
+
+fn parent_pid(pid: i32) -> Option<i32> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+
+    // The name can hold anything including spaces and parentheses, so skip past the last one
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    fn status(output: &str) -> SandboxStatus {
+        let (status, mut write) = SandboxStatus::pipe().unwrap();
+        write.write_all(output.as_bytes()).unwrap();
+        status
+    }
+
+    #[test]
+    fn reads_info_fd() {
+        // What bwrap writes with --info-fd, the pipe gets closed afterwards
+        let mut status = status(
+            "{\n    \"child-pid\": 42,\n    \"cgroup-namespace\": 4026531835,\n    \
+             \"pid-namespace\": 4026532290\n}\n",
+        );
+
+        let info = status.next::<SandboxInfo>().unwrap().unwrap();
+        assert_eq!(info.child_pid, 42);
+        assert_eq!(info.pid_namespace, Some(4026532290));
+        assert_eq!(info.net_namespace, None);
+        assert!(status.next::<ExitInfo>().unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_json_status_fd() {
+        let mut status = status(
+            "{ \"child-pid\": 42, \"user-namespace\": 4026532289 }\n\
+             { \"exit-code\": 3 }\n",
+        );
+
+        let info = status.next::<SandboxInfo>().unwrap().unwrap();
+        assert_eq!(info.child_pid, 42);
+        assert_eq!(info.user_namespace, Some(4026532289));
+        assert_eq!(status.next::<ExitInfo>().unwrap().unwrap().exit_code, 3);
+        assert!(status.next::<ExitInfo>().unwrap().is_none());
+    }
+
+    #[test]
+    fn skips_unknown_objects() {
+        let mut status = status("{ \"something-new\": true }\n{ \"exit-code\": 1 }\n");
+        assert_eq!(status.next::<ExitInfo>().unwrap().unwrap().exit_code, 1);
+    }
+
+    #[test]
+    fn reads_setup_error() {
+        let mut status = status("{\"setup-error\":{\"step\":\"creating /x\",\"errno\":2}}\n");
+
+        let error = status.next::<SetupInfo>().unwrap().unwrap().setup_error;
+        assert_eq!(error.step, "creating /x");
+        assert_eq!(error.errno, libc::ENOENT);
+    }
+}
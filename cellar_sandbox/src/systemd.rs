@@ -0,0 +1,163 @@
+use crate::{Result, SandboxError};
+
+use std::process::Command;
+
+const SYSTEMD_RUN: &str = "systemd-run";
+const SYSTEMCTL: &str = "systemctl";
+
+/// What systemd shows for properties which aren't known, like the memory use of a scope without
+/// memory accounting
+const UNSET: u64 = u64::MAX;
+
+/// A transient scope of the systemd instance of the user, which the sandbox gets started in. All
+/// of the processes in it are accounted for and limited together, and can be stopped together
+/// no matter how they were started.
+///
+/// The memory, cpu and io limits only work for controllers which are delegated to the user, the
+/// others are ignored by systemd.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemdScope {
+    unit: String,
+    memory_max: Option<u64>,
+    cpu_quota: Option<u32>,
+    io_weight: Option<u16>,
+}
+
+impl SystemdScope {
+    /// `name` can't be used by another unit of the user while the scope is running
+    pub fn new<T: Into<String>>(name: T) -> SystemdScope {
+        let name = name.into();
+
+        SystemdScope {
+            unit: match name.ends_with(".scope") {
+                true => name,
+                false => format!("{}.scope", name),
+            },
+            memory_max: None,
+            cpu_quota: None,
+            io_weight: None,
+        }
+    }
+
+    /// The name of the unit, including the `.scope`
+    pub fn unit(&self) -> &str {
+        &self.unit
+    }
+
+    /// The most memory the processes may use together in bytes, beyond which they get killed
+    pub fn memory_max(&mut self, bytes: u64) -> &mut SystemdScope {
+        self.memory_max = Some(bytes);
+        self
+    }
+
+    /// The cpu time the processes may use, in percent of a single cpu
+    pub fn cpu_quota(&mut self, percent: u32) -> &mut SystemdScope {
+        self.cpu_quota = Some(percent);
+        self
+    }
+
+    /// The share of io the processes get compared to other units, from 1 to 10000 where 100 is
+    /// the default
+    pub fn io_weight(&mut self, weight: u16) -> &mut SystemdScope {
+        self.io_weight = Some(weight);
+        self
+    }
+
+    /// A command which runs `cmd` in the scope. systemd-run execs it once the scope is there, so
+    /// it keeps the same pid.
+    ///
+    /// Only the program, arguments, env vars and working directory are carried over. Anything
+    /// else, like code to run with `pre_exec`, has to be added to the command this gives back.
+    pub fn wrap(&self, cmd: &Command) -> Command {
+        let mut scope = Command::new(SYSTEMD_RUN);
+        scope
+            .args(["--user", "--scope", "--quiet", "--collect"])
+            .arg(format!("--unit={}", self.unit));
+
+        if let Some(bytes) = self.memory_max {
+            scope.arg(format!("--property=MemoryMax={}", bytes));
+        }
+        if let Some(percent) = self.cpu_quota {
+            scope.arg(format!("--property=CPUQuota={}%", percent));
+        }
+        if let Some(weight) = self.io_weight {
+            scope.arg(format!("--property=IOWeight={}", weight));
+        }
+
+        scope.arg("--").arg(cmd.get_program()).args(cmd.get_args());
+
+        for (key, value) in cmd.get_envs() {
+            match value {
+                Some(value) => scope.env(key, value),
+                None => scope.env_remove(key),
+            };
+        }
+        if let Some(dir) = cmd.get_current_dir() {
+            scope.current_dir(dir);
+        }
+
+        scope
+    }
+}
+
+/// What the processes in a scope use together, as far as systemd keeps track of it
+#[derive(Debug, Clone, Default)]
+pub struct ScopeStats {
+    /// Bytes of memory in use
+    pub memory: Option<u64>,
+    /// Nanoseconds of cpu time used so far
+    pub cpu_time: Option<u64>,
+    pub tasks: Option<u64>,
+    pub io_read: Option<u64>,
+    pub io_written: Option<u64>,
+}
+
+impl ScopeStats {
+    fn parse(output: &str) -> Option<ScopeStats> {
+        let mut stats = ScopeStats::default();
+        let mut active = false;
+
+        for (key, value) in output.lines().filter_map(|x| x.split_once('=')) {
+            let number = value.parse::<u64>().ok().filter(|x| *x != UNSET);
+
+            match key {
+                "ActiveState" => active = value == "active",
+                "MemoryCurrent" => stats.memory = number,
+                "CPUUsageNSec" => stats.cpu_time = number,
+                "TasksCurrent" => stats.tasks = number,
+                "IOReadBytes" => stats.io_read = number,
+                "IOWriteBytes" => stats.io_written = number,
+                _ => (),
+            }
+        }
+
+        active.then_some(stats)
+    }
+}
+
+/// What the processes in the scope use right now, `None` if it isn't running
+pub fn stats(unit: &str) -> Result<Option<ScopeStats>> {
+    let output = Command::new(SYSTEMCTL)
+        .args(["--user", "show"])
+        .arg("--property=ActiveState,MemoryCurrent,CPUUsageNSec,TasksCurrent,IOReadBytes,IOWriteBytes")
+        .arg(unit)
+        .output()?;
+    if !output.status.success() {
+        return Err(SandboxError::SystemdFailed(output.status));
+    }
+
+    Ok(ScopeStats::parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Stops the scope, which kills every process in it
+pub fn stop(unit: &str) -> Result<()> {
+    let status = Command::new(SYSTEMCTL)
+        .args(["--user", "stop"])
+        .arg(unit)
+        .status()?;
+
+    match status.success() {
+        true => Ok(()),
+        false => Err(SandboxError::SystemdFailed(status)),
+    }
+}
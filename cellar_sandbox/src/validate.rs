@@ -0,0 +1,393 @@
+use crate::BubMount;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    /// bwrap will refuse to start, or the sandbox won't work
+    Error,
+}
+
+/// A problem with the mounts of a [`BubLauncher`](crate::BubLauncher), mounts are referred to by
+/// the order they were added in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    MissingSource {
+        mount: usize,
+        src: PathBuf,
+    },
+    Shadowed {
+        mount: usize,
+        dest: PathBuf,
+        by: usize,
+    },
+    DanglingSymlink {
+        mount: usize,
+        link: PathBuf,
+        target: PathBuf,
+    },
+    RequiredMissing {
+        path: PathBuf,
+    },
+    RequiredHidden {
+        path: PathBuf,
+        by: usize,
+    },
+}
+
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Diagnostic::MissingSource { .. } => Severity::Error,
+            Diagnostic::Shadowed { .. } => Severity::Warning,
+            Diagnostic::DanglingSymlink { .. } => Severity::Warning,
+            Diagnostic::RequiredMissing { .. } => Severity::Error,
+            Diagnostic::RequiredHidden { .. } => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnostic::MissingSource { mount, src } => {
+                write!(f, "mount #{}: {} does not exist", mount, src.display())
+            }
+            Diagnostic::Shadowed { mount, dest, by } => write!(
+                f,
+                "mount #{}: {} is hidden by mount #{}",
+                mount,
+                dest.display(),
+                by
+            ),
+            Diagnostic::DanglingSymlink {
+                mount,
+                link,
+                target,
+            } => write!(
+                f,
+                "mount #{}: {} points to {}, which is not in the sandbox",
+                mount,
+                link.display(),
+                target.display()
+            ),
+            Diagnostic::RequiredMissing { path } => {
+                write!(f, "{} is not mounted in the sandbox", path.display())
+            }
+            Diagnostic::RequiredHidden { path, by } => {
+                write!(f, "{} is hidden by mount #{}", path.display(), by)
+            }
+        }
+    }
+}
+
+impl BubMount {
+    /// Where the mount ends up inside of the sandbox
+    pub fn dest(&self) -> &Path {
+        match self {
+            BubMount::DevBind { dest, .. }
+            | BubMount::BindRO { dest, .. }
+            | BubMount::BindRW { dest, .. }
+            | BubMount::Symlink { dest, .. }
+            | BubMount::Overlay { dest, .. }
+            | BubMount::TmpOverlay { dest, .. }
+            | BubMount::RoOverlay { dest, .. } => dest,
+
+            BubMount::TmpFs { path, .. }
+            | BubMount::Proc { path }
+            | BubMount::Dir { path, .. }
+            | BubMount::File { path, .. }
+            | BubMount::BindData { path, .. }
+            | BubMount::BindDataRO { path, .. }
+            | BubMount::Chmod { path, .. }
+            | BubMount::RemountRO { path } => path,
+        }
+    }
+
+    /// Paths on the host which have to exist for the mount to work
+    pub fn sources(&self) -> Vec<&Path> {
+        match self {
+            BubMount::DevBind { src, .. }
+            | BubMount::BindRO { src, .. }
+            | BubMount::BindRW { src, .. } => vec![src],
+
+            BubMount::Overlay {
+                sources,
+                upper,
+                work,
+                ..
+            } => sources
+                .iter()
+                .chain([upper, work])
+                .map(PathBuf::as_path)
+                .collect(),
+            BubMount::TmpOverlay { sources, .. } | BubMount::RoOverlay { sources, .. } => {
+                sources.iter().map(PathBuf::as_path).collect()
+            }
+
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether the mount puts something from the host, or of its own, at `path`
+    fn supplies(&self, path: &Path) -> bool {
+        match self {
+            BubMount::DevBind { dest, .. }
+            | BubMount::BindRO { dest, .. }
+            | BubMount::BindRW { dest, .. }
+            | BubMount::Overlay { dest, .. }
+            | BubMount::TmpOverlay { dest, .. }
+            | BubMount::RoOverlay { dest, .. }
+            | BubMount::Proc { path: dest } => path.starts_with(dest),
+
+            BubMount::Symlink { dest, .. }
+            | BubMount::File { path: dest, .. }
+            | BubMount::BindData { path: dest, .. }
+            | BubMount::BindDataRO { path: dest, .. } => path == dest,
+
+            _ => false,
+        }
+    }
+
+    /// Whether the mount replaces whatever was at its destination before
+    fn covers(&self) -> bool {
+        !matches!(
+            self,
+            BubMount::Dir { .. } | BubMount::Chmod { .. } | BubMount::RemountRO { .. }
+        )
+    }
+}
+
+/// Checks the mounts in the order bwrap applies them, paths in `required` have to end up visible
+/// inside of the sandbox
+pub fn validate(mounts: &[BubMount], required: &[PathBuf]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for (idx, mount) in mounts.iter().enumerate() {
+        mount
+            .sources()
+            .into_iter()
+            .filter(|x| !x.exists())
+            .for_each(|src| {
+                diagnostics.push(Diagnostic::MissingSource {
+                    mount: idx,
+                    src: src.to_path_buf(),
+                })
+            });
+
+        if let Some(by) = shadowed_by(mounts, idx) {
+            diagnostics.push(Diagnostic::Shadowed {
+                mount: idx,
+                dest: mount.dest().to_path_buf(),
+                by,
+            });
+        }
+
+        if let BubMount::Symlink { src, dest } = mount {
+            // Relative links are resolved from the directory they are in
+            let target = match dest.parent() {
+                Some(parent) if src.is_relative() => parent.join(src),
+                _ => src.clone(),
+            };
+
+            if !resolves(mounts, &target) {
+                diagnostics.push(Diagnostic::DanglingSymlink {
+                    mount: idx,
+                    link: dest.clone(),
+                    target,
+                });
+            }
+        }
+    }
+
+    for path in required {
+        let provider = mounts.iter().rposition(|x| x.supplies(path));
+
+        match provider {
+            None => diagnostics.push(Diagnostic::RequiredMissing { path: path.clone() }),
+            Some(idx) => {
+                // Anything replacing the path afterwards can't supply it, or it would be the
+                // provider
+                let hidden = mounts
+                    .iter()
+                    .enumerate()
+                    .skip(idx + 1)
+                    .find(|(_, x)| x.covers() && path.starts_with(x.dest()));
+
+                if let Some((by, _)) = hidden {
+                    diagnostics.push(Diagnostic::RequiredHidden {
+                        path: path.clone(),
+                        by,
+                    });
+                }
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/// Finds a later mount which replaces the destination of the mount at `idx`, or one of its parents
+fn shadowed_by(mounts: &[BubMount], idx: usize) -> Option<usize> {
+    let dest = mounts[idx].dest();
+
+    mounts
+        .iter()
+        .enumerate()
+        .skip(idx + 1)
+        .find(|(_, x)| x.covers() && dest.starts_with(x.dest()))
+        .map(|(by, _)| by)
+}
+
+/// Whether `target` exists inside of the sandbox, as far as we can tell without starting it
+fn resolves(mounts: &[BubMount], target: &Path) -> bool {
+    // Something created at or below the target makes it exist
+    if mounts.iter().any(|x| x.dest().starts_with(target)) {
+        return true;
+    }
+
+    let provider = mounts
+        .iter()
+        .rev()
+        .find(|x| x.covers() && target.starts_with(x.dest()));
+
+    match provider {
+        Some(BubMount::DevBind { src, dest })
+        | Some(BubMount::BindRO { src, dest })
+        | Some(BubMount::BindRW { src, dest }) => src
+            .join(target.strip_prefix(dest).unwrap_or(target))
+            .exists(),
+        Some(BubMount::Proc { .. }) => true,
+        Some(_) | None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bind(src: &Path, dest: &str) -> BubMount {
+        BubMount::BindRO {
+            src: src.to_path_buf(),
+            dest: dest.into(),
+        }
+    }
+
+    fn tmpfs(path: &str) -> BubMount {
+        BubMount::TmpFs {
+            path: path.into(),
+            perms: None,
+            size: None,
+        }
+    }
+
+    #[test]
+    fn reports_missing_sources() {
+        let missing = std::env::temp_dir().join(format!("cellar-missing-{}", std::process::id()));
+        let mounts = [bind(&std::env::temp_dir(), "/a"), bind(&missing, "/b")];
+
+        let diagnostics = validate(&mounts, &[]);
+        assert_eq!(
+            diagnostics,
+            [Diagnostic::MissingSource {
+                mount: 1,
+                src: missing
+            }]
+        );
+        assert_eq!(diagnostics[0].severity(), Severity::Error);
+    }
+
+    #[test]
+    fn reports_shadowed_mounts() {
+        let mounts = [bind(&std::env::temp_dir(), "/data/x"), tmpfs("/data")];
+
+        let diagnostics = validate(&mounts, &[]);
+        assert_eq!(
+            diagnostics,
+            [Diagnostic::Shadowed {
+                mount: 0,
+                dest: "/data/x".into(),
+                by: 1
+            }]
+        );
+        assert_eq!(diagnostics[0].severity(), Severity::Warning);
+    }
+
+    #[test]
+    fn reports_dangling_symlinks() {
+        let mounts = [
+            bind(Path::new("/"), "/host"),
+            tmpfs("/links"),
+            BubMount::Symlink {
+                src: "host".into(),
+                dest: "/good".into(),
+            },
+            BubMount::Symlink {
+                src: "/nowhere".into(),
+                dest: "/links/bad".into(),
+            },
+        ];
+
+        let diagnostics = validate(&mounts, &[]);
+        assert_eq!(
+            diagnostics,
+            [Diagnostic::DanglingSymlink {
+                mount: 3,
+                link: "/links/bad".into(),
+                target: "/nowhere".into()
+            }]
+        );
+        assert_eq!(diagnostics[0].severity(), Severity::Warning);
+    }
+
+    #[test]
+    fn reports_missing_required_paths() {
+        let mounts = [tmpfs("/tmp")];
+
+        let diagnostics = validate(&mounts, &["/tmp/reaper".into()]);
+        assert_eq!(
+            diagnostics,
+            [Diagnostic::RequiredMissing {
+                path: "/tmp/reaper".into()
+            }]
+        );
+        assert_eq!(diagnostics[0].severity(), Severity::Error);
+    }
+
+    #[test]
+    fn reports_hidden_required_paths() {
+        let mounts = [bind(&std::env::temp_dir(), "/tmp/reaper"), tmpfs("/tmp")];
+
+        let diagnostics = validate(&mounts, &["/tmp/reaper".into()]);
+        assert_eq!(
+            diagnostics,
+            [
+                Diagnostic::Shadowed {
+                    mount: 0,
+                    dest: "/tmp/reaper".into(),
+                    by: 1
+                },
+                Diagnostic::RequiredHidden {
+                    path: "/tmp/reaper".into(),
+                    by: 1
+                }
+            ]
+        );
+        assert_eq!(diagnostics[1].severity(), Severity::Error);
+    }
+
+    #[test]
+    fn accepts_required_paths_mounted_last() {
+        let mounts = [
+            tmpfs("/tmp"),
+            bind(&std::env::temp_dir(), "/tmp/reaper"),
+            BubMount::RemountRO {
+                path: "/tmp".into(),
+            },
+        ];
+
+        assert_eq!(validate(&mounts, &["/tmp/reaper".into()]), []);
+    }
+}
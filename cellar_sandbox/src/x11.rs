@@ -0,0 +1,221 @@
+use crate::{Result, SandboxError, X11Sandbox};
+
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+pub const X11_SOCKET_DIR: &str = "/tmp/.X11-unix";
+
+/// Nested servers get display numbers from here on, so they stay clear of the ones of the user
+const FIRST_NESTED_DISPLAY: u32 = 100;
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The authorization scheme clients of the nested servers have to use
+const COOKIE_NAME: &[u8] = b"MIT-MAGIC-COOKIE-1";
+const COOKIE_LENGTH: usize = 16;
+/// Xauthority entries of this family match any host and display, which the sandbox needs as it
+/// sees the display under another number
+const FAMILY_WILD: u16 = 0xffff;
+
+/// An X server which runs separately from the one of the user, so programs using it can't see or
+/// send input to the windows of anything else
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum X11Server {
+    /// Shows the display in a window
+    Xephyr,
+    /// Shows every window on its own, through an xpra client
+    Xpra,
+    /// Doesn't show anything at all
+    Xvfb,
+}
+
+impl X11Server {
+    /// xpra sets up the authorization of its server itself, the others only let clients in which
+    /// have the cookie from the Xauthority file
+    fn needs_authority(&self) -> bool {
+        !matches!(self, Self::Xpra)
+    }
+
+    fn command(&self, display: u32, (width, height): (u32, u32), auth: Option<&Path>) -> Command {
+        let mut cmd = match self {
+            Self::Xephyr => Command::new("Xephyr"),
+            Self::Xpra => Command::new("xpra"),
+            Self::Xvfb => Command::new("Xvfb"),
+        };
+
+        match self {
+            Self::Xephyr => cmd
+                .arg(format!(":{}", display))
+                .args(["-nolisten", "tcp", "-resizeable", "-screen"])
+                .arg(format!("{}x{}", width, height)),
+            Self::Xpra => cmd.arg("start").arg(format!(":{}", display)).args([
+                "--daemon=no",
+                "--attach=yes",
+                "--mdns=no",
+                "--notifications=no",
+            ]),
+            Self::Xvfb => cmd
+                .arg(format!(":{}", display))
+                .args(["-nolisten", "tcp", "-screen", "0"])
+                .arg(format!("{}x{}x24", width, height)),
+        };
+
+        if let Some(auth) = auth {
+            cmd.arg("-auth").arg(auth);
+        }
+
+        cmd
+    }
+}
+
+impl From<X11Server> for X11Sandbox {
+    fn from(server: X11Server) -> X11Sandbox {
+        match server {
+            X11Server::Xephyr => X11Sandbox::XEPHYR,
+            X11Server::Xpra => X11Sandbox::XPRA,
+            X11Server::Xvfb => X11Sandbox::XVFB,
+        }
+    }
+}
+
+/// A running nested X server, which gets stopped once this is dropped
+#[derive(Debug)]
+pub struct NestedServer {
+    child: Child,
+    display: u32,
+    authority: Option<PathBuf>,
+}
+
+impl NestedServer {
+    /// Starts the server on a free display, and waits until it takes connections
+    pub fn start(server: X11Server, size: (u32, u32)) -> Result<NestedServer> {
+        let display = (FIRST_NESTED_DISPLAY..FIRST_NESTED_DISPLAY + 100)
+            .find(|x| !socket_path(*x).exists() && !lock_path(*x).exists())
+            .ok_or(SandboxError::NoFreeDisplay)?;
+
+        let authority = match server.needs_authority() {
+            true => Some(write_authority(display)?),
+            false => None,
+        };
+
+        let child = server
+            .command(display, size, authority.as_deref())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()?;
+        let mut nested = NestedServer {
+            child,
+            display,
+            authority,
+        };
+
+        let start = Instant::now();
+        while !nested.socket().exists() {
+            if nested.child.try_wait()?.is_some() || start.elapsed() > STARTUP_TIMEOUT {
+                return Err(SandboxError::X11Server(server));
+            }
+
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        Ok(nested)
+    }
+
+    pub fn display(&self) -> u32 {
+        self.display
+    }
+
+    pub fn socket(&self) -> PathBuf {
+        socket_path(self.display)
+    }
+
+    /// The Xauthority file clients have to use to connect, `None` for servers which take care of
+    /// that themselves
+    pub fn authority(&self) -> Option<&Path> {
+        self.authority.as_deref()
+    }
+}
+
+impl Drop for NestedServer {
+    fn drop(&mut self) {
+        // xpra cleans up after itself when asked nicely, which killing it outright doesn't allow
+        unsafe {
+            libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM);
+        }
+
+        let _ = self.child.wait();
+
+        if let Some(authority) = &self.authority {
+            let _ = std::fs::remove_file(authority);
+        }
+    }
+}
+
+/// The socket of a display, inside of [`X11_SOCKET_DIR`]
+pub fn socket_path(display: u32) -> PathBuf {
+    Path::new(X11_SOCKET_DIR).join(format!("X{}", display))
+}
+
+fn lock_path(display: u32) -> PathBuf {
+    PathBuf::from(format!("/tmp/.X{}-lock", display))
+}
+
+/// Writes an Xauthority file with a fresh cookie for the display, which only the user can read
+fn write_authority(display: u32) -> Result<PathBuf> {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    let path = dir.join(format!("cellar-xauth-{}", display));
+
+    let mut cookie = [0; COOKIE_LENGTH];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut cookie)?;
+
+    // Left over from a server which wasn't stopped, a new one can't be created on top of it
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?
+        .write_all(&authority_entry(&cookie))?;
+
+    Ok(path)
+}
+
+/// An Xauthority entry consists of the family followed by the address, display number, name and
+/// data of the authorization, each with its length in front
+fn authority_entry(cookie: &[u8]) -> Vec<u8> {
+    let mut entry = FAMILY_WILD.to_be_bytes().to_vec();
+
+    for field in [&b""[..], b"", COOKIE_NAME, cookie] {
+        entry.extend_from_slice(&(field.len() as u16).to_be_bytes());
+        entry.extend_from_slice(field);
+    }
+
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_authority_entry() {
+        let entry = authority_entry(&[0xab; COOKIE_LENGTH]);
+
+        assert_eq!(entry[..6], [0xff, 0xff, 0, 0, 0, 0]);
+        assert_eq!(entry[6..8], [0, 18]);
+        assert_eq!(&entry[8..26], b"MIT-MAGIC-COOKIE-1");
+        assert_eq!(entry[26..28], [0, 16]);
+        assert_eq!(entry[28..], [0xab; COOKIE_LENGTH]);
+    }
+}
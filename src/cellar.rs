@@ -1,21 +1,46 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::FromStr;
 
 use camino::{Utf8Path, Utf8PathBuf};
-use cellar_sandbox::{BubLauncher, BubMount, EnvVar, FirejailLauncher};
-use log::{debug, error};
+use cellar_sandbox::x11::{self, X11_SOCKET_DIR};
+use cellar_sandbox::{firejail, status, systemd};
+use cellar_sandbox::{render, resolve_env};
+use cellar_sandbox::{
+    BubLauncher, BubMount, Bwrap, Diagnostic, EnvVar, FirejailLauncher, FirejailProfile,
+    FirejailSandbox, LandlockRuleset, NestedServer, OciBundle, PortAccess, Rlimit, SandboxInfo,
+    SandboxStatus, SandboxUser, ScopeStats, SeccompPolicy, Severity, SystemdScope, Untranslated,
+    X11Server,
+};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::drives::{self, DriveMapping, DriveType};
+use crate::env::{self, EnvMap};
+use crate::expand::Expander;
+use crate::mapping::{PathMapping, WinTarget, SANDBOX_PREFIX};
+
 pub type Result<T, E = CellarError> = std::result::Result<T, E>;
 
 pub const WINE_CELLAR_CONFIG: &str = "winecellar.json";
+pub const SESSION_FILE: &str = "session.json";
+pub const USER_CONFIG: &str = "cellar/config.json";
 pub const REAPER_LOCAL_LOCATIONS: &str = ".:target/debug/:target/release";
 pub const REAPER_BIN_NAME: &str = "cellar-reaper";
+pub const REAPER_SANDBOX_PATH: &str = "/tmp/reaper";
+
+/// The identity used inside of the sandbox, so the prefix never learns about the real user
+pub const SANDBOX_USER: &str = "cellar";
+pub const SANDBOX_HOSTNAME: &str = "cellar";
+pub const SANDBOX_HOME: &str = "/home";
+pub const SANDBOX_UID: u32 = 1000;
+pub const SANDBOX_GID: u32 = 1000;
 
-fn get_reaper_path() -> Result<PathBuf> {
+pub fn get_reaper_path() -> Result<PathBuf> {
     // First, check if the reaper binary can be found in the debug or release targets of cargo, or
     // if it can be found in the cwd
     which::which_in(REAPER_BIN_NAME, Some(REAPER_LOCAL_LOCATIONS), ".")
@@ -28,6 +53,21 @@ fn get_reaper_path() -> Result<PathBuf> {
         .map_err(|_| CellarError::ReaperMissing)
 }
 
+fn warn_missing_env(vars: &[EnvVar]) {
+    let missing = vars
+        .iter()
+        .filter(|x| x.is_missing())
+        .map(EnvVar::key)
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        warn!(
+            "Passed through env vars which aren't set: {}",
+            missing.join(", ")
+        );
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum CellarError {
     #[error(transparent)]
@@ -41,6 +81,48 @@ pub enum CellarError {
 
     #[error(transparent)]
     NonUtf8Path(#[from] camino::FromPathError),
+
+    #[error("invalid windows path \"{0}\"")]
+    InvalidWindowsPath(String),
+
+    #[error("drive {0}: cannot be mapped")]
+    UnsupportedDrive(char),
+
+    #[error("the prefix has no user profile, has wine been run yet?")]
+    MissingProfile,
+
+    #[error("line {0} of the env file is not KEY=VALUE")]
+    InvalidEnvLine(usize),
+
+    #[error("\"${{{0}}}\" is not defined")]
+    UndefinedVariable(String),
+
+    #[error("\"${{{0}}}\" refers to itself")]
+    RecursiveVariable(String),
+
+    #[error("unclosed \"${{\" in \"{0}\"")]
+    UnclosedVariable(String),
+
+    #[error("there is no env profile named \"{0}\"")]
+    UnknownProfile(String),
+
+    #[error("invalid drive letter \"{0}\"")]
+    InvalidDriveLetter(String),
+
+    #[error("wine exited with {0}")]
+    WineFailed(std::process::ExitStatus),
+
+    #[error(transparent)]
+    SandboxError(#[from] cellar_sandbox::SandboxError),
+
+    #[error("the sandbox has {0} problem(s) which keep it from starting")]
+    InvalidSandbox(usize),
+
+    #[error("the systemd scope {0} of another sandbox is still running")]
+    ScopeRunning(String),
+
+    #[error("{0} is a symlink, nothing is created beneath it")]
+    SymlinkInPrefix(Utf8PathBuf),
 }
 
 #[derive(Debug)]
@@ -55,10 +137,18 @@ impl WineCellar {
         let cfg_path = path.join(WINE_CELLAR_CONFIG);
         let file = File::open(&cfg_path)?;
 
-        Ok(WineCellar {
+        let mut cellar = WineCellar {
             path: path.to_path_buf(),
             config: serde_json::from_reader(file)?,
-        })
+        };
+
+        // Configs from before the machine id get one now, which has to stay the same afterwards
+        if cellar.config.machine_id.is_empty() {
+            cellar.config.machine_id = generate_machine_id()?;
+            cellar.save_config()?;
+        }
+
+        Ok(cellar)
     }
 
     pub fn create<T: AsRef<Path>>(path: T) -> Result<WineCellar> {
@@ -67,7 +157,10 @@ impl WineCellar {
 
         let cellar = WineCellar {
             path: path.to_path_buf(),
-            config: CellarConfig::default(),
+            config: CellarConfig {
+                machine_id: generate_machine_id()?,
+                ..CellarConfig::default()
+            },
         };
 
         cellar.save_config()?;
@@ -85,22 +178,57 @@ impl WineCellar {
     // Returns a `Command` that will start firejail with the proper profile and arguments
     // along with a wineserver with the current prefix. It is up to the caller to use proper
     // arguments or environmental modifications for the specified program.
-    pub fn run(&self) -> Command {
+    pub fn run(&self) -> Result<Command> {
+        let mut cmd = self.firejail_run()?;
+        cmd.arg(self.wine_bin_path());
+        Ok(cmd)
+    }
+
+    /// Starts a firejail sandbox named after the cellar, followed by the program to run in it
+    pub fn firejail_run(&self) -> Result<Command> {
         let mut launcher = FirejailLauncher::default();
+        launcher.name(self.firejail_name());
+
+        // firejail starts and stops the nested server itself
+        if let Some(server) = self.config.display.server() {
+            launcher.x11(server.into());
+        }
 
         launcher.whitelist(std::fs::canonicalize(self.path.to_path_buf()).unwrap());
 
-        let mut cmd = launcher.command();
+        // firejail needs root to bind folders, so it has nothing to put the mappings in place with
+        for mapping in self.get_mappings() {
+            warn!(
+                "{} is only mapped into the prefix with bubblewrap, it is left out",
+                mapping.host
+            );
+        }
+
+        // The links to them are made by `WineCellar::prepare_prefix`, but they'd stay hidden if
+        // they are in the home
+        let vars = self.merged_env(&[])?;
+        let expander = Expander::new(&self.path, &vars)?;
+        for drive in self.get_drives() {
+            launcher.whitelist(expander.expand_path(&drive.host)?.into());
+        }
+        if let Some(profile) = &self.config.firejail_profile {
+            launcher.profile(profile.clone().into());
+        }
+        for limit in &self.config.rlimits {
+            launcher.rlimit(*limit);
+        }
+
+        let mut cmd = launcher.command()?;
 
-        cmd.arg(self.wine_bin_path());
         cmd.env("WINEPREFIX", self.wine_prefix_path());
-        cmd.envs(
-            self.get_env_vars()
-                .iter()
-                .map(|x| x.clone())
-                .map(|x| x.to_key_value())
-                .collect::<Vec<(String, String)>>(),
-        );
+        let env = self.effective_env(&[])?;
+        warn_missing_env(&env);
+        for (key, value) in resolve_env(env, |x| std::env::var(x).ok())? {
+            match value {
+                Some(value) => cmd.env(key, value),
+                None => cmd.env_remove(key),
+            };
+        }
 
         match self.config.sync {
             WineSync::AUTO => cmd.env("WINEESYNC", "1").env("WINEFSYNC", "1"),
@@ -109,16 +237,271 @@ impl WineCellar {
             WineSync::WINESYNC => todo!("winesync"),
         };
 
-        cmd
+        Ok(self.scoped(cmd))
+    }
+
+    /// The systemd scope the sandbox gets started in, if the cellar uses one. It is named like
+    /// the firejail sandbox, so it is unique between cellars as well.
+    pub fn systemd_scope(&self) -> Option<SystemdScope> {
+        if !self.config.systemd_scope {
+            return None;
+        }
+
+        let mut scope = SystemdScope::new(self.firejail_name());
+        if let Some(bytes) = self.config.memory_max {
+            scope.memory_max(bytes);
+        }
+        if let Some(percent) = self.config.cpu_quota {
+            scope.cpu_quota(percent);
+        }
+        if let Some(weight) = self.config.io_weight {
+            scope.io_weight(weight);
+        }
+
+        Some(scope)
+    }
+
+    /// Runs the command in the systemd scope of the cellar, if it uses one. Commands from
+    /// [`WineCellar::bwrap_run`] already are.
+    pub fn scoped(&self, cmd: Command) -> Command {
+        match self.systemd_scope() {
+            Some(scope) => scope.wrap(&cmd),
+            None => cmd,
+        }
+    }
+
+    /// Fails if the scope is still taken by another sandbox of the cellar, since systemd can't
+    /// start a second unit with the same name
+    pub fn check_scope(&self) -> Result<()> {
+        match self.scope_stats()? {
+            Some(_) => Err(CellarError::ScopeRunning(
+                self.systemd_scope().unwrap().unit().to_string(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// What the sandbox uses according to its systemd scope, if it has one which is running
+    pub fn scope_stats(&self) -> Result<Option<ScopeStats>> {
+        match self.systemd_scope() {
+            Some(scope) => Ok(systemd::stats(scope.unit())?),
+            None => Ok(None),
+        }
+    }
+
+    /// Starts the nested X server the cellar is configured to use, if any. It gets stopped once
+    /// it is dropped, so it has to be kept around for as long as the sandbox runs.
+    pub fn start_display(&self) -> Result<Option<NestedServer>> {
+        match self.config.display.server() {
+            Some(server) => {
+                let nested = NestedServer::start(server, self.config.display_size)?;
+                info!("Started {:?} on display :{}", server, nested.display());
+                Ok(Some(nested))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// `display` is the nested X server from [`WineCellar::start_display`], without it the
+    /// sandbox has no display at all unless it uses the one of the host
+    pub fn bwrap_run(&self, display: Option<&NestedServer>) -> Result<Command> {
+        let mut cmd = self.checked_launcher(display)?.command()?;
+        cmd.arg("--");
+        Ok(cmd)
+    }
+
+    /// Like [`WineCellar::bwrap_run`], but the command has to be spawned through
+    /// [`SandboxHandle::spawn`] to find out about the sandbox
+    pub fn bwrap_session(
+        &self,
+        display: Option<&NestedServer>,
+    ) -> Result<(Command, SandboxStatus)> {
+        let (mut cmd, status) = self.checked_launcher(display)?.command_with_status()?;
+        cmd.arg("--");
+        Ok((cmd, status))
+    }
+
+    /// Runs `program` in the same sandbox [`WineCellar::bwrap_session`] would, for
+    /// [`Backend::NATIVE`]
+    pub fn native_session<S: AsRef<OsStr>>(
+        &self,
+        display: Option<&NestedServer>,
+        program: S,
+    ) -> Result<(Command, SandboxStatus)> {
+        Ok(self.checked_launcher(display)?.native_command(program)?)
+    }
+
+    /// Shows what [`WineCellar::bwrap_run`] followed by `program` would run, even if the sandbox
+    /// has problems which would keep it from starting
+    pub fn bwrap_render<I, S>(&self, program: I) -> Result<String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let program = program
+            .into_iter()
+            .map(|x| render::quote(x.as_ref()).into_owned())
+            .collect::<Vec<_>>();
+
+        Ok(format!(
+            "{} -- {}",
+            self.bwrap_launcher(None)?.render()?,
+            program.join(" ")
+        ))
+    }
+
+    /// Describes the bubblewrap sandbox as an OCI bundle at `path` which runs `program`, without
+    /// a nested display as the runtime can't start one
+    pub fn oci_bundle<P: AsRef<Path>>(&self, path: P, program: Vec<String>) -> Result<OciBundle> {
+        Ok(self.sandbox_launcher(None)?.oci_bundle(path, program)?)
+    }
+
+    /// Looks for problems with the sandbox without starting it, or changing anything in the
+    /// prefix
+    pub fn check(&self) -> Result<Vec<Diagnostic>> {
+        let overlay = self.overlay_path();
+
+        // The overlay directories only get created once the sandbox starts
+        let diagnostics = self.bwrap_launcher(None)?.validate().into_iter().filter(
+            |x| !matches!(x, Diagnostic::MissingSource { src, .. } if src.starts_with(&overlay)),
+        );
+
+        Ok(diagnostics.collect())
+    }
+
+    /// Gets the prefix ready for the sandbox to start, with the directories the mappings are
+    /// mounted on, the links of the drives and the directories of the overlay
+    pub fn prepare_prefix(&self) -> Result<()> {
+        let vars = self.merged_env(&[])?;
+        let expander = Expander::new(&self.path, &vars)?;
+
+        let prefix = self.wine_prefix_path();
+        let prefix: &Utf8Path = prefix.as_path().try_into()?;
+
+        if self.config.prefix_mode == PrefixMode::OVERLAY {
+            std::fs::create_dir_all(self.overlay_path().join("upper"))?;
+            std::fs::create_dir_all(self.overlay_path().join("work"))?;
+        }
+
+        // The landlock and firejail backends have nothing to mount them with
+        if matches!(self.config.backend, Backend::BUBBLEWRAP | Backend::NATIVE) {
+            for mapping in self.get_mappings() {
+                mapping.prepare(prefix)?;
+            }
+        }
+
+        for drive in self.get_drives() {
+            let host = expander.expand_path(&drive.host)?;
+            drives::link_drive(prefix, drive.letter, &host)?;
+        }
+
+        Ok(())
+    }
+
+    /// Refuses to hand out a launcher which bwrap would fail to start
+    fn checked_launcher(&self, display: Option<&NestedServer>) -> Result<BubLauncher> {
+        self.check_scope()?;
+        self.prepare_prefix()?;
+
+        let l = self.bwrap_launcher(display)?;
+        let mut errors = 0;
+
+        for diagnostic in l.validate() {
+            match diagnostic.severity() {
+                Severity::Warning => warn!("{}", diagnostic),
+                Severity::Error => {
+                    error!("{}", diagnostic);
+                    errors += 1;
+                }
+            }
+        }
+
+        match errors {
+            0 => Ok(l),
+            errors => Err(CellarError::InvalidSandbox(errors)),
+        }
     }
 
-    pub fn bwrap_run(&self) -> Command {
+    fn bwrap_launcher(&self, display: Option<&NestedServer>) -> Result<BubLauncher> {
+        let mut l = self.sandbox_launcher(display)?;
+
+        // The native backend sets up the same sandbox by itself
+        if self.config.backend != Backend::NATIVE {
+            let vars = self.merged_env(&[])?;
+            let bwrap = match &self.config.bwrap {
+                Some(path) => Some(Expander::new(&self.path, &vars)?.expand_path(path)?),
+                None => None,
+            };
+            l.executable(Bwrap::locate(bwrap)?);
+        }
+
+        Ok(l)
+    }
+
+    /// The whole sandbox, without looking for the bwrap which runs it
+    fn sandbox_launcher(&self, display: Option<&NestedServer>) -> Result<BubLauncher> {
+        let mut l = self.base_launcher(display)?;
+
+        if let Some(profile) = &self.config.firejail_profile {
+            let profile = FirejailProfile::load(profile)?;
+            for untranslated in profile.apply_bubblewrap(&mut l, Path::new(SANDBOX_HOME)) {
+                warn!("Ignoring firejail profile entry {}", untranslated);
+            }
+        }
+
+        if self.config.hardened {
+            l.harden();
+        }
+
+        // Keeps the env out of `ps`, since it can contain tokens for game launchers
+        l.args_fd(true);
+
+        if let Some(policy) = &self.config.seccomp {
+            l.seccomp(policy.clone());
+        }
+
+        Ok(l)
+    }
+
+    /// The entries of the profile which bubblewrap can't use, with the mounts of this cellar
+    pub fn untranslated_profile(&self, profile: &FirejailProfile) -> Result<Vec<Untranslated>> {
+        let mut l = self.base_launcher(None)?;
+        Ok(profile.apply_bubblewrap(&mut l, Path::new(SANDBOX_HOME)))
+    }
+
+    /// The sandbox without the firejail profile and hardening on top
+    fn base_launcher(&self, display: Option<&NestedServer>) -> Result<BubLauncher> {
+        // Paths in the config can refer to the env vars which are used for every launch
+        let vars = self.merged_env(&[])?;
+        let expander = Expander::new(&self.path, &vars)?;
+
         let mut l = BubLauncher::default();
+        if let Some(scope) = self.systemd_scope() {
+            l.systemd_scope(scope);
+        }
 
-        l.mount(BubMount::tmpfs("/tmp"))
-            .mount(BubMount::dev_bind(self.wine_prefix_path(), "/wineprefix"))
+        let mut tmp = BubMount::tmpfs("/tmp");
+        if let Some(size) = self.config.tmp_size {
+            tmp = tmp.size(size);
+        }
+
+        let prefix_mount = match self.config.prefix_mode {
+            PrefixMode::DIRECT => BubMount::dev_bind(self.wine_prefix_path(), SANDBOX_PREFIX),
+            PrefixMode::EPHEMERAL => {
+                BubMount::tmp_overlay([self.wine_prefix_path()], SANDBOX_PREFIX)
+            }
+            PrefixMode::OVERLAY => BubMount::overlay(
+                [self.wine_prefix_path()],
+                self.overlay_path().join("upper"),
+                self.overlay_path().join("work"),
+                SANDBOX_PREFIX,
+            ),
+        };
+
+        l.mount(tmp)
+            .mount(prefix_mount)
             .mount(BubMount::dev_bind("/run", "/run"))
-            .mount(BubMount::tmpfs("/home"))
+            .mount(BubMount::tmpfs(SANDBOX_HOME))
             .mount(BubMount::proc("/proc"))
             .mount(BubMount::dev_bind(
                 "/run/user/1000/pulse/native",
@@ -132,20 +515,76 @@ impl WineCellar {
             .mount(BubMount::symlink("/usr/lib32", "/lib32"))
             .mount(BubMount::symlink("/usr/lib64", "/lib64"));
 
-        let reaper_path = get_reaper_path().expect("failed to find reaper");
-        l.mount(BubMount::bind_ro(reaper_path, "/tmp/reaper"));
+        let reaper_path = get_reaper_path()?;
+        l.mount(BubMount::bind_ro(reaper_path, REAPER_SANDBOX_PATH))
+            .require_path(REAPER_SANDBOX_PATH);
+
+        l.user(SandboxUser::new(SANDBOX_USER, SANDBOX_UID, SANDBOX_GID))
+            .hostname(SANDBOX_HOSTNAME)
+            .machine_id(&self.config.machine_id);
+
+        for limit in &self.config.rlimits {
+            l.rlimit(*limit);
+        }
 
-        l.env(("HOME", "/home"))
+        l.env(("HOME", SANDBOX_HOME))
+            .env(("USER", SANDBOX_USER))
+            .env(("LOGNAME", SANDBOX_USER))
             .env(("WINEPREFIX", "/wineprefix"))
-            .env(("DISPLAY", ":0"))
             .env(("XDG_RUNTIME_DIR", "/run/user/1000"))
-            .env(("XAUTHORITY", "/tmp/xauthority"))
             .env(("LANG", "en_US.UTF-8"))
-            .mount(BubMount::bind_ro("/etc/fonts", "/etc/fonts"))
-            .mount(BubMount::dev_bind("/tmp/.X11-unix", "/tmp/.X11-unix"))
-            .mount(BubMount::bind_ro("/home/me/.Xauthority", "/tmp/xauthority"));
+            .mount(BubMount::bind_ro("/etc/fonts", "/etc/fonts"));
         //.mount(BubMount::bind_rw(self.wine_prefix_path(), "/home/wine"));
 
+        match (self.config.display, display) {
+            (DisplayMode::HOST, _) => {
+                let host_display = std::env::var("DISPLAY").unwrap_or_else(|_| ":0".into());
+                l.env(("DISPLAY", host_display))
+                    .mount(BubMount::dev_bind(X11_SOCKET_DIR, X11_SOCKET_DIR));
+
+                let xauthority = std::env::var_os("XAUTHORITY")
+                    .map(PathBuf::from)
+                    .or_else(|| std::env::var_os("HOME").map(|x| Path::new(&x).join(".Xauthority")))
+                    .filter(|x| x.exists());
+                if let Some(xauthority) = xauthority {
+                    l.env(("XAUTHORITY", "/tmp/xauthority"))
+                        .mount(BubMount::bind_ro(xauthority, "/tmp/xauthority"));
+                }
+            }
+            // Only the socket of the nested server shows up, the one of the host stays hidden
+            (_, Some(nested)) => {
+                l.env(("DISPLAY", ":0"))
+                    .mount(BubMount::bind_rw(nested.socket(), x11::socket_path(0)));
+
+                if let Some(authority) = nested.authority() {
+                    l.env(("XAUTHORITY", "/tmp/xauthority"))
+                        .mount(BubMount::bind_ro(authority, "/tmp/xauthority"));
+                }
+            }
+            (_, None) => debug!("No nested X server was started, the sandbox has no display"),
+        }
+
+        // These have to come after the prefix itself, otherwise they'd be hidden by it
+        let prefix = self.wine_prefix_path();
+        let prefix: &Utf8Path = prefix.as_path().try_into()?;
+        for mapping in self.get_mappings() {
+            let dest = mapping.sandbox_path(prefix)?;
+            let host = expander.expand_path(&mapping.host)?;
+
+            if mapping.read_only {
+                l.mount(BubMount::bind_ro(host, dest));
+            } else {
+                l.mount(BubMount::bind_rw(host, dest));
+            }
+        }
+
+        // Drives are mounted at the same location as on the host so the links in `dosdevices`
+        // resolve the same way in and outside of the sandbox
+        for drive in self.get_drives() {
+            let host = expander.expand_path(&drive.host)?;
+            l.mount(BubMount::bind_rw(host.clone(), host));
+        }
+
         match self.config.sync {
             WineSync::AUTO => l.env(("WINEESYNC", "1")).env(("WINEFSYNC", "1")),
             WineSync::ESYNC => l.env(("WINEESYNC", "1")),
@@ -153,18 +592,143 @@ impl WineCellar {
             WineSync::WINESYNC => todo!("winesync"),
         };
 
-        let mut cmd = l.command();
-        cmd.arg("--");
-        cmd
+        Ok(l)
     }
 
-    pub fn bwrap_wine(&self) -> Command {
-        let mut cmd = self.bwrap_run();
+    pub fn bwrap_wine(&self) -> Result<Command> {
+        let mut cmd = self.bwrap_run(None)?;
         cmd.arg("/usr/bin/wine");
-        cmd
+        Ok(cmd)
+    }
+
+    /// Runs the program right on the host, for [`Backend::LANDLOCK`]. It has to restrict itself
+    /// with [`WineCellar::landlock_ruleset`], like the reaper does.
+    pub fn landlock_run<S: AsRef<OsStr>>(
+        &self,
+        program: S,
+        display: Option<&NestedServer>,
+    ) -> Result<Command> {
+        let mut cmd = Command::new(program);
+        cmd.env("WINEPREFIX", self.wine_prefix_path());
+
+        if let Some(nested) = display {
+            cmd.env("DISPLAY", format!(":{}", nested.display()));
+            if let Some(authority) = nested.authority() {
+                cmd.env("XAUTHORITY", authority);
+            }
+        }
+
+        match self.config.sync {
+            WineSync::AUTO => cmd.env("WINEESYNC", "1").env("WINEFSYNC", "1"),
+            WineSync::ESYNC => cmd.env("WINEESYNC", "1"),
+            WineSync::FSYNC => cmd.env("WINEFSYNC", "1"),
+            WineSync::WINESYNC => todo!("winesync"),
+        };
+
+        Ok(cmd)
+    }
+
+    /// The Landlock rules the program in the cellar gets restricted with, if any
+    pub fn landlock_ruleset(&self) -> Result<Option<LandlockRuleset>> {
+        let mut ruleset = match self.config.backend {
+            Backend::LANDLOCK => self.host_ruleset()?,
+            Backend::BUBBLEWRAP | Backend::NATIVE if self.config.landlock => {
+                let mut ruleset = self.sandbox_launcher(None)?.landlock_ruleset();
+                // bwrap puts the user and group files there itself, they aren't mounts of ours
+                ruleset.read_only("/etc");
+                ruleset
+            }
+            _ => return Ok(None),
+        };
+
+        if let Some(ports) = &self.config.landlock_ports {
+            ruleset.restrict_network(true);
+            for port in ports {
+                ruleset.port(*port, PortAccess::Connect);
+            }
+        }
+
+        Ok(Some(ruleset))
+    }
+
+    /// Restricts cellar itself with [`WineCellar::landlock_ruleset`], so everything it starts from
+    /// then on is as well
+    pub fn landlock_self(&self) -> Result<()> {
+        if let Some(ruleset) = self.landlock_ruleset()? {
+            let enforcement = ruleset.restrict_self()?;
+            info!("Restricted with landlock ABI {}", enforcement.abi);
+
+            if ruleset.restricts_network() && !enforcement.network {
+                warn!("The kernel can't restrict the network with landlock, it is left open");
+            }
+            for path in enforcement.missing {
+                debug!("{} doesn't exist, it isn't granted", path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Grants what a bwrap sandbox would mount, but at the locations they have on the host
+    fn host_ruleset(&self) -> Result<LandlockRuleset> {
+        let vars = self.merged_env(&[])?;
+        let expander = Expander::new(&self.path, &vars)?;
+
+        let mut ruleset = LandlockRuleset::default();
+        for path in ["/usr", "/etc", "/bin", "/sbin", "/lib", "/lib32", "/lib64"] {
+            ruleset.read_only(path);
+        }
+
+        ruleset
+            .read_only(get_reaper_path()?)
+            .read_write(self.path.as_std_path())
+            .read_write("/tmp")
+            .read_write("/dev")
+            .read_write("/proc")
+            .read_write(expander.expand("${XDG_RUNTIME_DIR}")?);
+
+        let xauthority = std::env::var_os("XAUTHORITY")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|x| Path::new(&x).join(".Xauthority")));
+        if let Some(xauthority) = xauthority {
+            ruleset.read_only(xauthority);
+        }
+
+        // Without a mount namespace there is nothing to put the mappings in place with
+        for mapping in self.get_mappings() {
+            warn!(
+                "{} is only mapped into the prefix with bubblewrap, it is left out",
+                mapping.host
+            );
+        }
+
+        // The links to them are made by `WineCellar::prepare_prefix`
+        for drive in self.get_drives() {
+            let host = expander.expand_path(&drive.host)?;
+            ruleset.read_write(host);
+        }
+
+        Ok(ruleset)
     }
 
-    pub fn kill(&self) {
+    pub fn kill(&self) -> Result<()> {
+        // The scope has everything which runs in the sandbox, even what the wineserver doesn't
+        // know about
+        if let Some(scope) = self.systemd_scope() {
+            let stopped = systemd::stats(scope.unit()).and_then(|stats| match stats {
+                Some(_) => {
+                    info!("Stopping systemd scope {}", scope.unit());
+                    systemd::stop(scope.unit())
+                }
+                None => Ok(()),
+            });
+
+            // The wineserver can still be stopped without it
+            if let Err(e) = stopped {
+                error!("Failed to stop systemd scope {}: {}", scope.unit(), e);
+            }
+        }
+
         Command::new("wineserver")
             .arg("-k")
             .arg("-w") // wait for wineserver to terminate
@@ -173,19 +737,282 @@ impl WineCellar {
             .unwrap()
             .wait()
             .unwrap();
+
+        Ok(())
     }
 
-    pub fn set_env_var<T: Into<EnvVar>>(&mut self, env: T) {
-        self.config.extra_env.push(env.into());
+    /// The env vars of a profile of this cellar, or the ones which are always used without one
+    pub fn env(&self, profile: Option<&str>) -> Result<&EnvMap> {
+        match profile {
+            Some(name) => self
+                .config
+                .env_profiles
+                .get(name)
+                .ok_or_else(|| CellarError::UnknownProfile(name.to_string())),
+            None => Ok(&self.config.extra_env),
+        }
     }
 
-    pub fn get_env_vars(&self) -> &Vec<EnvVar> {
-        &self.config.extra_env
+    /// Like [`WineCellar::env`], but creates the profile if it doesn't exist yet
+    pub fn env_mut(&mut self, profile: Option<&str>) -> &mut EnvMap {
+        match profile {
+            Some(name) => self
+                .config
+                .env_profiles
+                .entry(name.to_string())
+                .or_default(),
+            None => &mut self.config.extra_env,
+        }
     }
 
-    #[allow(dead_code)]
-    pub fn get_env_var<T: AsRef<str>>(&self, var: T) -> Option<&EnvVar> {
-        self.get_env_vars().iter().find(|x| x.key() == var.as_ref())
+    /// Every profile this cellar can use, the ones of the cellar hide global ones with the same
+    /// name
+    pub fn env_profiles(&self) -> Result<BTreeMap<String, (EnvMap, bool)>> {
+        let mut profiles = UserConfig::load()?
+            .env_profiles
+            .into_iter()
+            .map(|(name, env)| (name, (env, true)))
+            .collect::<BTreeMap<_, _>>();
+
+        for (name, env) in &self.config.env_profiles {
+            profiles.insert(name.clone(), (env.clone(), false));
+        }
+
+        Ok(profiles)
+    }
+
+    pub fn remove_env_profile(&mut self, name: &str) -> Option<EnvMap> {
+        self.config.default_profiles.retain(|x| x != name);
+        self.config.env_profiles.remove(name)
+    }
+
+    pub fn default_profiles(&self) -> &[String] {
+        &self.config.default_profiles
+    }
+
+    /// Makes the profile apply to every launch, or only when it is asked for
+    pub fn set_profile_default(&mut self, name: &str, enabled: bool) -> Result<()> {
+        if !self.env_profiles()?.contains_key(name) {
+            return Err(CellarError::UnknownProfile(name.to_string()));
+        }
+
+        self.config.default_profiles.retain(|x| x != name);
+        if enabled {
+            self.config.default_profiles.push(name.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// The env vars of the cellar with the default profiles and `profiles` on top of them, in
+    /// that order, with their values expanded
+    pub fn effective_env(&self, profiles: &[String]) -> Result<Vec<EnvVar>> {
+        let vars = self.merged_env(profiles)?;
+        let expander = Expander::new(&self.path, &vars)?;
+
+        vars.iter()
+            .cloned()
+            .map(|x| expander.expand_var(x))
+            .collect()
+    }
+
+    fn merged_env(&self, profiles: &[String]) -> Result<Vec<EnvVar>> {
+        let available = self.env_profiles()?;
+
+        let mut names = self.config.default_profiles.clone();
+        for name in profiles {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+
+        let mut layers = vec![self.config.extra_env.as_slice()];
+        for name in &names {
+            match available.get(name) {
+                Some((env, _)) => layers.push(env.as_slice()),
+                None => return Err(CellarError::UnknownProfile(name.clone())),
+            }
+        }
+
+        Ok(env::merge(layers))
+    }
+
+    /// The effective env vars with everything passed through already looked up, for handing them
+    /// to something running inside of the sandbox
+    pub fn captured_env(&self, profiles: &[String]) -> Result<Vec<EnvVar>> {
+        let vars = self.effective_env(profiles)?;
+        warn_missing_env(&vars);
+
+        let mut env = Vec::new();
+        for var in vars {
+            env.extend(var.capture()?);
+        }
+
+        Ok(env)
+    }
+
+    /// Maps a host folder into the prefix, replacing any mapping with the same target
+    pub fn add_mapping(&mut self, mapping: PathMapping) {
+        self.remove_mapping(&mapping.target);
+        self.config.mappings.push(mapping);
+    }
+
+    pub fn remove_mapping(&mut self, target: &WinTarget) -> Option<PathMapping> {
+        let idx = self
+            .config
+            .mappings
+            .iter()
+            .position(|x| &x.target == target)?;
+        Some(self.config.mappings.remove(idx))
+    }
+
+    pub fn get_mappings(&self) -> &Vec<PathMapping> {
+        &self.config.mappings
+    }
+
+    /// Adds a drive letter backed by a folder on the host
+    pub fn add_drive(&mut self, drive: DriveMapping) -> Result<()> {
+        drives::link_drive(&self.path, drive.letter, &drive.host)?;
+
+        self.config.drives.retain(|x| x.letter != drive.letter);
+        self.config.drives.push(drive);
+
+        Ok(())
+    }
+
+    /// Removes a drive letter from the prefix, whether or not it was added by cellar
+    pub fn remove_drive(&mut self, letter: char) -> Result<bool> {
+        self.config.drives.retain(|x| x.letter != letter);
+        drives::unlink_drive(&self.path, letter)
+    }
+
+    pub fn get_drives(&self) -> &Vec<DriveMapping> {
+        &self.config.drives
+    }
+
+    /// Sets the type of the drive in the registry of the prefix, which requires starting wine
+    pub fn set_drive_type(&self, letter: char, kind: DriveType) -> Result<()> {
+        let status = self
+            .bwrap_wine()?
+            .arg("reg")
+            .arg("add")
+            .arg(r"HKLM\Software\Wine\Drives")
+            .arg("/v")
+            .arg(format!("{}:", letter))
+            .arg("/d")
+            .arg(kind.registry_value())
+            .arg("/f")
+            .status()?;
+
+        match status.success() {
+            true => Ok(()),
+            false => Err(CellarError::WineFailed(status)),
+        }
+    }
+
+    pub fn session_path(&self) -> Utf8PathBuf {
+        self.path.join(SESSION_FILE)
+    }
+
+    /// Remembers the sandbox which is running in this cellar, so it can be joined later
+    pub fn record_session(&self, info: &SandboxInfo) -> Result<()> {
+        let file = File::create(self.session_path())?;
+        serde_json::to_writer_pretty(file, info)?;
+
+        Ok(())
+    }
+
+    pub fn clear_session(&self) -> Result<()> {
+        match std::fs::remove_file(self.session_path()) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The sandbox running in this cellar, if there is one
+    pub fn session(&self) -> Result<Option<SandboxInfo>> {
+        let file = match File::open(self.session_path()) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let info: SandboxInfo = serde_json::from_reader(file)?;
+
+        // The sandbox may have died without cleaning up after itself
+        match status::is_alive(info.child_pid) {
+            true => Ok(Some(info)),
+            false => Ok(None),
+        }
+    }
+
+    /// The name of the firejail sandbox of this cellar, which has to be unique between cellars
+    pub fn firejail_name(&self) -> String {
+        let name = self
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .chars()
+            .map(
+                |x| match x.is_ascii_alphanumeric() || x == '-' || x == '_' {
+                    true => x,
+                    false => '-',
+                },
+            )
+            .collect::<String>();
+
+        let id = self.config.machine_id.get(..8).unwrap_or_default();
+        format!("cellar-{}-{}", name, id)
+    }
+
+    /// The running firejail sandbox of this cellar
+    pub fn firejail_session(&self) -> Result<Option<FirejailSandbox>> {
+        let name = self.firejail_name();
+
+        Ok(firejail::list()?
+            .into_iter()
+            .find(|x| x.name.as_deref() == Some(name.as_str())))
+    }
+
+    /// The pid of whatever runs the sandbox of the cellar, if it is running
+    pub fn sandbox_pid(&self) -> Result<Option<i32>> {
+        match self.config.backend {
+            Backend::BUBBLEWRAP | Backend::LANDLOCK | Backend::NATIVE => {
+                Ok(self.session()?.map(|x| x.child_pid))
+            }
+            Backend::FIREJAIL => Ok(self.firejail_session()?.map(|x| x.pid)),
+        }
+    }
+
+    /// Stops the running sandbox of the cellar, returns whether there was one
+    pub fn stop(&self) -> Result<bool> {
+        match self.config.backend {
+            Backend::BUBBLEWRAP | Backend::LANDLOCK | Backend::NATIVE => match self.session()? {
+                Some(info) => {
+                    status::kill(info.child_pid)?;
+                    self.clear_session()?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+            Backend::FIREJAIL => match self.firejail_session()? {
+                Some(_) => {
+                    firejail::shutdown(&self.firejail_name())?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+        }
+    }
+
+    /// Where the changes to the prefix go with [`PrefixMode::OVERLAY`], which has to be outside of
+    /// the prefix itself
+    pub fn overlay_path(&self) -> Utf8PathBuf {
+        let abs_prefix = self.wine_prefix_path();
+        let mut name = abs_prefix.file_name().unwrap_or_default().to_os_string();
+        name.push(".overlay");
+
+        Utf8PathBuf::try_from(abs_prefix.with_file_name(name)).expect("prefix path is utf-8")
     }
 
     #[allow(dead_code)]
@@ -226,16 +1053,171 @@ impl AsMut<CellarConfig> for WineCellar {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CellarConfig {
     pub sandbox: bool,
+
+    /// What runs the sandbox
+    #[serde(default)]
+    pub backend: Backend,
+
     pub sync: WineSync,
-    extra_env: Vec<EnvVar>,
+
+    /// Values are expanded when launching, see [`Expander`], so a `$$` or `${` which is meant
+    /// literally has to be written as `$$$$` or `$${`. Configs from before values were expanded
+    /// may need to be changed for that.
+    extra_env: EnvMap,
+
+    /// Sets of env vars which can be turned on when launching
+    #[serde(default)]
+    env_profiles: BTreeMap<String, EnvMap>,
+
+    /// The env profiles which are used for every launch
+    #[serde(default)]
+    default_profiles: Vec<String>,
+
+    #[serde(default)]
+    mappings: Vec<PathMapping>,
+
+    #[serde(default)]
+    drives: Vec<DriveMapping>,
+
+    /// The syscall filter used with bubblewrap, `null` turns it off
+    #[serde(default = "default_seccomp")]
+    pub seccomp: Option<SeccompPolicy>,
+
+    #[serde(default)]
+    pub prefix_mode: PrefixMode,
+
+    /// Caps the size of `/tmp` inside of the sandbox, in bytes
+    #[serde(default)]
+    pub tmp_size: Option<u64>,
+
+    /// The bwrap binary to use, otherwise it is looked up in `$PATH`
+    #[serde(default)]
+    pub bwrap: Option<Utf8PathBuf>,
+
+    /// What the sandbox sees in `/etc/machine-id`, generated once when the cellar is created
+    #[serde(default)]
+    pub machine_id: String,
+
+    /// Drops all capabilities and prevents the creation of nested user namespaces
+    #[serde(default = "default_hardened")]
+    pub hardened: bool,
+
+    /// Restricts the program in a bubblewrap sandbox with Landlock as well, the landlock backend
+    /// always does
+    #[serde(default)]
+    pub landlock: bool,
+
+    /// The TCP ports programs may connect to with Landlock, where the kernel supports restricting
+    /// them. Without these the network is left alone.
+    #[serde(default)]
+    pub landlock_ports: Option<Vec<u16>>,
+
+    /// A firejail profile, which is translated as far as possible when using bubblewrap
+    #[serde(default)]
+    pub firejail_profile: Option<Utf8PathBuf>,
+
+    /// Resource limits of the processes in the sandbox, which the landlock backend leaves out
+    #[serde(default)]
+    pub rlimits: Vec<Rlimit>,
+
+    /// Starts the sandbox in a systemd scope of the user, which the limits below apply to
+    #[serde(default)]
+    pub systemd_scope: bool,
+
+    /// The most memory the scope may use, in bytes
+    #[serde(default)]
+    pub memory_max: Option<u64>,
+
+    /// The cpu time the scope may use, in percent of a single cpu
+    #[serde(default)]
+    pub cpu_quota: Option<u32>,
+
+    /// The share of io the scope gets compared to other units, from 1 to 10000
+    #[serde(default)]
+    pub io_weight: Option<u16>,
+
+    /// Which X server programs in the sandbox get to use
+    #[serde(default)]
+    pub display: DisplayMode,
+
+    /// The size of the screen of nested X servers
+    #[serde(default = "default_display_size")]
+    pub display_size: (u32, u32),
+}
+
+fn default_display_size() -> (u32, u32) {
+    (1280, 720)
+}
+
+fn default_hardened() -> bool {
+    true
+}
+
+fn generate_machine_id() -> Result<String> {
+    // The kernel hands out a fresh random uuid every time this is read
+    let uuid = std::fs::read_to_string("/proc/sys/kernel/random/uuid")?;
+    Ok(uuid.trim().replace('-', ""))
+}
+
+fn default_seccomp() -> Option<SeccompPolicy> {
+    Some(SeccompPolicy::wine())
+}
+
+/// Settings shared by every cellar of the user
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UserConfig {
+    /// Env profiles every cellar can use
+    #[serde(default)]
+    pub env_profiles: BTreeMap<String, EnvMap>,
+}
+
+impl UserConfig {
+    /// Lives at `$XDG_CONFIG_HOME/cellar/config.json`, or in `~/.config` without it
+    pub fn path() -> Option<PathBuf> {
+        let config = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|x| PathBuf::from(x).join(".config")))?;
+
+        Some(config.join(USER_CONFIG))
+    }
+
+    /// Reads the config, which is empty if it doesn't exist
+    pub fn load() -> Result<UserConfig> {
+        match Self::path().map(File::open) {
+            Some(Ok(file)) => Ok(serde_json::from_reader(file)?),
+            Some(Err(e)) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(UserConfig::default()),
+        }
+    }
 }
 
 impl Default for CellarConfig {
     fn default() -> CellarConfig {
         CellarConfig {
             sandbox: true,
+            backend: Backend::default(),
             sync: WineSync::default(),
-            extra_env: Vec::default(),
+            extra_env: EnvMap::default(),
+            env_profiles: BTreeMap::default(),
+            default_profiles: Vec::default(),
+            mappings: Vec::default(),
+            drives: Vec::default(),
+            seccomp: default_seccomp(),
+            prefix_mode: PrefixMode::default(),
+            tmp_size: None,
+            bwrap: None,
+            machine_id: String::new(),
+            hardened: default_hardened(),
+            landlock: false,
+            landlock_ports: None,
+            firejail_profile: None,
+            rlimits: Vec::new(),
+            systemd_scope: false,
+            memory_max: None,
+            cpu_quota: None,
+            io_weight: None,
+            display: DisplayMode::default(),
+            display_size: default_display_size(),
         }
     }
 }
@@ -269,3 +1251,109 @@ impl FromStr for WineSync {
         }
     }
 }
+
+/// How the prefix is made available inside of the sandbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PrefixMode {
+    /// Changes are written straight to the prefix
+    DIRECT,
+    /// Changes are thrown away once the sandbox exits
+    EPHEMERAL,
+    /// Changes are kept next to the prefix, which itself stays untouched
+    OVERLAY,
+}
+
+impl Default for PrefixMode {
+    fn default() -> PrefixMode {
+        PrefixMode::DIRECT
+    }
+}
+
+impl FromStr for PrefixMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_ref() {
+            "DIRECT" => Ok(PrefixMode::DIRECT),
+            "EPHEMERAL" => Ok(PrefixMode::EPHEMERAL),
+            "OVERLAY" => Ok(PrefixMode::OVERLAY),
+            _ => Err(format!("Unknown prefix mode \"{}\"", s)),
+        }
+    }
+}
+
+/// Which X server the sandbox gets, anything but the one of the host keeps programs from seeing and
+/// sending input to other windows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisplayMode {
+    HOST,
+    XEPHYR,
+    XPRA,
+    /// Headless, nothing is shown
+    XVFB,
+}
+
+impl DisplayMode {
+    pub const fn server(&self) -> Option<X11Server> {
+        match self {
+            DisplayMode::HOST => None,
+            DisplayMode::XEPHYR => Some(X11Server::Xephyr),
+            DisplayMode::XPRA => Some(X11Server::Xpra),
+            DisplayMode::XVFB => Some(X11Server::Xvfb),
+        }
+    }
+}
+
+impl Default for DisplayMode {
+    fn default() -> DisplayMode {
+        DisplayMode::HOST
+    }
+}
+
+impl FromStr for DisplayMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_ref() {
+            "HOST" => Ok(DisplayMode::HOST),
+            "XEPHYR" => Ok(DisplayMode::XEPHYR),
+            "XPRA" => Ok(DisplayMode::XPRA),
+            "XVFB" => Ok(DisplayMode::XVFB),
+            _ => Err(format!("Unknown display mode \"{}\"", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Backend {
+    BUBBLEWRAP,
+    FIREJAIL,
+    /// Needs no namespaces at all, for hosts which don't allow bwrap to create them.
+    ///
+    /// All of `/tmp` is writable with it, as wine keeps the socket of the wineserver in there.
+    /// That includes the X11 sockets of the host in `/tmp/.X11-unix`, so a nested display
+    /// doesn't keep programs from connecting to the display of the host.
+    LANDLOCK,
+    /// The same sandbox as bwrap, set up by cellar itself so there's no bwrap binary needed
+    NATIVE,
+}
+
+impl Default for Backend {
+    fn default() -> Backend {
+        Backend::BUBBLEWRAP
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_ref() {
+            "BUBBLEWRAP" | "BWRAP" => Ok(Backend::BUBBLEWRAP),
+            "FIREJAIL" => Ok(Backend::FIREJAIL),
+            "LANDLOCK" => Ok(Backend::LANDLOCK),
+            "NATIVE" => Ok(Backend::NATIVE),
+            _ => Err(format!("Unknown backend \"{}\"", s)),
+        }
+    }
+}
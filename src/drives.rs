@@ -0,0 +1,163 @@
+use std::str::FromStr;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::cellar::{CellarError, Result};
+use crate::mapping::read_reg_section;
+
+const DRIVES_KEY: &str = r"[Software\\Wine\\Drives]";
+
+/// A drive letter which cellar keeps pointed at a folder on the host.
+///
+/// The folder is mounted at the same path inside of the sandbox, so the `dosdevices` symlink
+/// resolves both inside and outside of it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DriveMapping {
+    pub letter: char,
+    pub host: Utf8PathBuf,
+}
+
+/// A drive letter as found in the `dosdevices` folder of a prefix
+#[derive(Debug, Clone)]
+pub struct Drive {
+    pub letter: char,
+    pub target: Utf8PathBuf,
+    pub kind: Option<DriveType>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DriveType {
+    HardDisk,
+    Network,
+    CdRom,
+    Floppy,
+}
+
+impl DriveType {
+    /// The value wine expects in `HKLM\Software\Wine\Drives`
+    pub const fn registry_value(&self) -> &'static str {
+        match self {
+            Self::HardDisk => "hd",
+            Self::Network => "network",
+            Self::CdRom => "cdrom",
+            Self::Floppy => "floppy",
+        }
+    }
+}
+
+impl FromStr for DriveType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "hd" | "harddisk" => Ok(DriveType::HardDisk),
+            "network" => Ok(DriveType::Network),
+            "cdrom" => Ok(DriveType::CdRom),
+            "floppy" => Ok(DriveType::Floppy),
+            _ => Err(format!("Unknown drive type \"{}\"", s)),
+        }
+    }
+}
+
+/// Checks that `letter` is a valid drive letter, returning it in lowercase
+pub fn drive_letter<T: AsRef<str>>(letter: T) -> Result<char> {
+    let letter = letter.as_ref();
+    let mut chars = letter.trim_end_matches(':').chars();
+
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphabetic() => Ok(c.to_ascii_lowercase()),
+        _ => Err(CellarError::InvalidDriveLetter(letter.to_string())),
+    }
+}
+
+fn dosdevice(prefix: &Utf8Path, letter: char) -> Utf8PathBuf {
+    prefix.join("dosdevices").join(format!("{}:", letter))
+}
+
+/// Lists the drive letters of a prefix, along with the type configured in the registry
+pub fn list_drives(prefix: &Utf8Path) -> Result<Vec<Drive>> {
+    let kinds = match std::fs::read_to_string(prefix.join("system.reg")) {
+        Ok(registry) => read_reg_section(&registry, DRIVES_KEY),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut drives = Vec::new();
+
+    for entry in prefix.join("dosdevices").read_dir_utf8()? {
+        let entry = entry?;
+
+        // Entries like `d::` point at the raw device rather than the mount point
+        let name = entry.file_name();
+        let letter = match drive_letter(name) {
+            Ok(letter) if name.len() == 2 && name.ends_with(':') => letter,
+            _ => continue,
+        };
+
+        let kind = kinds
+            .iter()
+            .find(|x| x.0.eq_ignore_ascii_case(&format!("{}:", letter)))
+            .and_then(|x| x.1.parse().ok());
+
+        drives.push(Drive {
+            letter,
+            target: entry.path().read_link_utf8()?,
+            kind,
+        });
+    }
+
+    drives.sort_by_key(|x| x.letter);
+    Ok(drives)
+}
+
+/// Points the drive letter at `target`, replacing whatever it pointed at before
+pub fn link_drive(prefix: &Utf8Path, letter: char, target: &Utf8Path) -> Result<()> {
+    let link = dosdevice(prefix, letter);
+
+    if link.read_link_utf8().ok().as_deref() == Some(target) {
+        return Ok(());
+    }
+
+    debug!("linking {} to {}", link, target);
+    unlink_drive(prefix, letter)?;
+    std::os::unix::fs::symlink(target, link)?;
+
+    Ok(())
+}
+
+/// Removes the drive letter, returns false if it did not exist
+pub fn unlink_drive(prefix: &Utf8Path, letter: char) -> Result<bool> {
+    match std::fs::remove_file(dosdevice(prefix, letter)) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_raw_devices() {
+        let path = std::env::temp_dir().join(format!("cellar-drives-{}", std::process::id()));
+        let prefix = Utf8PathBuf::try_from(path).unwrap();
+        let _ = std::fs::remove_dir_all(&prefix);
+        std::fs::create_dir_all(prefix.join("dosdevices")).unwrap();
+
+        std::os::unix::fs::symlink("../drive_c", prefix.join("dosdevices/c:")).unwrap();
+        std::os::unix::fs::symlink("/mnt/cdrom", prefix.join("dosdevices/d:")).unwrap();
+        std::os::unix::fs::symlink("/dev/sr0", prefix.join("dosdevices/d::")).unwrap();
+
+        let drives = list_drives(&prefix).unwrap();
+        let drives = drives
+            .iter()
+            .map(|x| (x.letter, x.target.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(drives, [('c', "../drive_c"), ('d', "/mnt/cdrom")]);
+
+        std::fs::remove_dir_all(prefix).unwrap();
+    }
+}
@@ -0,0 +1,289 @@
+use cellar_sandbox::EnvVar;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::cellar::{CellarError, Result};
+
+/// The extra env vars of a cellar, with at most one entry per env var.
+///
+/// Entries keep the order they were first added in, which matters for appending and prepending.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<EnvVar>", into = "Vec<EnvVar>")]
+pub struct EnvMap(Vec<EnvVar>);
+
+impl EnvMap {
+    /// Adds the env var, replacing the entry for the same key in place if there is one
+    pub fn set(&mut self, var: EnvVar) -> Option<EnvVar> {
+        match self.0.iter_mut().find(|x| x.key() == var.key()) {
+            Some(entry) => Some(std::mem::replace(entry, var)),
+            None => {
+                self.0.push(var);
+                None
+            }
+        }
+    }
+
+    pub fn unset(&mut self, key: &str) -> Option<EnvVar> {
+        let idx = self.0.iter().position(|x| x.key() == key)?;
+        Some(self.0.remove(idx))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&EnvVar> {
+        self.0.iter().find(|x| x.key() == key)
+    }
+
+    pub fn as_slice(&self) -> &[EnvVar] {
+        &self.0
+    }
+
+    /// Reads a `.env` file, a line with only a key passes the env var through from the host
+    pub fn parse_dotenv(content: &str) -> Result<Vec<EnvVar>> {
+        let mut vars = Vec::new();
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+            let var = match line.split_once('=') {
+                Some((key, value)) => EnvVar::KeyValue(key.trim().into(), unquote(value.trim())),
+                None => EnvVar::Pass(line.into()),
+            };
+
+            if !valid_key(var.key()) {
+                return Err(CellarError::InvalidEnvLine(idx + 1));
+            }
+
+            vars.push(var);
+        }
+
+        Ok(vars)
+    }
+
+    /// Writes the env vars in the format [`EnvMap::parse_dotenv`] reads, leaving out the ones
+    /// which it has no way of showing
+    pub fn to_dotenv(&self) -> String {
+        let mut out = String::new();
+
+        for var in &self.0 {
+            match var {
+                EnvVar::KeyValue(key, value) => {
+                    out.push_str(&format!("{}={}\n", key, quote(value)))
+                }
+                EnvVar::Pass(key) => out.push_str(&format!("{}\n", key)),
+                var => warn!("Leaving out {}, it can't be written to an env file", var),
+            }
+        }
+
+        out
+    }
+}
+
+impl From<Vec<EnvVar>> for EnvMap {
+    fn from(vars: Vec<EnvVar>) -> EnvMap {
+        // Older configs could have the same env var more than once, the last one used to win
+        let mut map = EnvMap::default();
+        vars.into_iter().for_each(|x| {
+            map.set(x);
+        });
+
+        map
+    }
+}
+
+impl From<EnvMap> for Vec<EnvVar> {
+    fn from(map: EnvMap) -> Vec<EnvVar> {
+        map.0
+    }
+}
+
+/// Puts the layers of env vars on top of each other, an env var replaces the ones before it with the
+/// same key unless it adds onto them
+pub fn merge<'a, I>(layers: I) -> Vec<EnvVar>
+where
+    I: IntoIterator<Item = &'a [EnvVar]>,
+{
+    let mut merged: Vec<EnvVar> = Vec::new();
+
+    for var in layers.into_iter().flatten() {
+        if !matches!(var, EnvVar::Append { .. } | EnvVar::Prepend { .. }) {
+            merged.retain(|x| x.key() != var.key());
+        }
+
+        merged.push(var.clone());
+    }
+
+    merged
+}
+
+fn valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+
+    matches!(chars.next(), Some(x) if x.is_ascii_alphabetic() || x == '_')
+        && chars.all(|x| x.is_ascii_alphanumeric() || x == '_')
+}
+
+fn quote(value: &str) -> String {
+    let mut out = String::from('"');
+
+    for x in value.chars() {
+        match x {
+            '"' | '\\' | '$' | '`' => {
+                out.push('\\');
+                out.push(x);
+            }
+            '\n' => out.push_str("\\n"),
+            x => out.push(x),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+/// Strips the quotes off a value, escapes only work in double quoted values
+fn unquote(value: &str) -> String {
+    let quoted =
+        |quote: char| value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote);
+
+    if quoted('\'') {
+        return value[1..value.len() - 1].to_string();
+    }
+
+    if quoted('"') {
+        let mut out = String::new();
+        let mut chars = value[1..value.len() - 1].chars();
+
+        while let Some(x) = chars.next() {
+            match x {
+                '\\' => match chars.next() {
+                    Some('n') => out.push('\n'),
+                    Some(x) => out.push(x),
+                    None => out.push('\\'),
+                },
+                x => out.push(x),
+            }
+        }
+
+        return out;
+    }
+
+    value.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_value(key: &str, value: &str) -> EnvVar {
+        EnvVar::KeyValue(key.into(), value.into())
+    }
+
+    #[test]
+    fn round_trips_dotenv() {
+        let vars = [
+            key_value("PLAIN", "value"),
+            key_value("QUOTES", r#"say "hi""#),
+            key_value("SLASH", r"C:\windows\"),
+            key_value("DOLLAR", "$HOME and ${USER}"),
+            key_value("LINES", "one\ntwo"),
+            key_value("EMPTY", ""),
+            EnvVar::Pass("DISPLAY".into()),
+        ];
+
+        let map = EnvMap::from(vars.to_vec());
+        let dotenv = map.to_dotenv();
+        assert_eq!(EnvMap::parse_dotenv(&dotenv).unwrap(), vars);
+        assert_eq!(dotenv.lines().count(), vars.len());
+    }
+
+    #[test]
+    fn parses_dotenv() {
+        let vars = EnvMap::parse_dotenv(
+            "# comment\n\
+             \n\
+             export A = 'single $quoted\\n'\n\
+             B=\"x\\\"y\\\\z\"\n\
+             C=unquoted\n\
+             DISPLAY\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vars,
+            [
+                key_value("A", "single $quoted\\n"),
+                key_value("B", "x\"y\\z"),
+                key_value("C", "unquoted"),
+                EnvVar::Pass("DISPLAY".into()),
+            ]
+        );
+        assert!(matches!(
+            EnvMap::parse_dotenv("A=1\n1B=2\n"),
+            Err(CellarError::InvalidEnvLine(2))
+        ));
+    }
+
+    #[test]
+    fn keeps_one_entry_per_key() {
+        let mut map = EnvMap::from(vec![
+            key_value("A", "1"),
+            key_value("B", "2"),
+            key_value("A", "3"),
+        ]);
+        assert_eq!(map.as_slice(), [key_value("A", "3"), key_value("B", "2")]);
+
+        // Replacing keeps the place of the entry
+        assert_eq!(map.set(key_value("A", "4")), Some(key_value("A", "3")));
+        assert_eq!(map.set(key_value("C", "5")), None);
+        assert_eq!(
+            map.as_slice(),
+            [
+                key_value("A", "4"),
+                key_value("B", "2"),
+                key_value("C", "5")
+            ]
+        );
+
+        assert_eq!(map.unset("B"), Some(key_value("B", "2")));
+        assert_eq!(map.unset("B"), None);
+        assert_eq!(map.get("C"), Some(&key_value("C", "5")));
+    }
+
+    #[test]
+    fn merges_layers() {
+        let base = [
+            key_value("A", "1"),
+            key_value("B", "2"),
+            EnvVar::append("PATH", "/opt/bin", ":"),
+        ];
+        let profile = [
+            key_value("A", "3"),
+            EnvVar::Unset("B".into()),
+            EnvVar::prepend("PATH", "/game/bin", ":"),
+        ];
+
+        assert_eq!(
+            merge([&base[..], &profile[..]]),
+            [
+                EnvVar::append("PATH", "/opt/bin", ":"),
+                key_value("A", "3"),
+                EnvVar::Unset("B".into()),
+                EnvVar::prepend("PATH", "/game/bin", ":"),
+            ]
+        );
+
+        // An env var set again drops what was added onto it before
+        let later = [key_value("PATH", "/usr/bin")];
+        assert_eq!(
+            merge([&base[..], &profile[..], &later[..]]),
+            [
+                key_value("A", "3"),
+                EnvVar::Unset("B".into()),
+                key_value("PATH", "/usr/bin"),
+            ]
+        );
+    }
+}
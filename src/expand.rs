@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use cellar_sandbox::EnvVar;
+
+use crate::cellar::{CellarError, Result};
+
+/// Expands `${NAME}` in configured values, so a config doesn't have to hardcode paths which differ
+/// between users. `$$` stands for a plain `$`.
+///
+/// Names refer to the built in values first, and then to the literal env vars of the cellar, which
+/// are expanded themselves.
+#[derive(Debug)]
+pub struct Expander<'a> {
+    builtins: HashMap<&'static str, String>,
+    vars: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> Expander<'a> {
+    pub fn new(cellar: &Utf8Path, vars: &'a [EnvVar]) -> Result<Expander<'a>> {
+        let uid = std::fs::metadata("/proc/self")?.uid();
+        let cellar = std::fs::canonicalize(cellar)
+            .ok()
+            .and_then(|x| Utf8PathBuf::try_from(x).ok())
+            .unwrap_or_else(|| cellar.to_path_buf());
+
+        let mut builtins = HashMap::new();
+        builtins.insert("UID", uid.to_string());
+        builtins.insert(
+            "XDG_RUNTIME_DIR",
+            std::env::var("XDG_RUNTIME_DIR").unwrap_or_else(|_| format!("/run/user/{}", uid)),
+        );
+        if let Ok(home) = std::env::var("HOME") {
+            builtins.insert("HOME", home);
+        }
+        if let Some(name) = cellar.file_name() {
+            builtins.insert("CELLAR_NAME", name.to_string());
+        }
+        builtins.insert("CELLAR", cellar.into_string());
+
+        let vars = vars
+            .iter()
+            .filter_map(|x| match x {
+                EnvVar::KeyValue(key, value) => Some((key.as_str(), value.as_str())),
+                _ => None,
+            })
+            .collect();
+
+        Ok(Expander { builtins, vars })
+    }
+
+    pub fn expand(&self, value: &str) -> Result<String> {
+        self.expand_with(value, &mut Vec::new())
+    }
+
+    pub fn expand_path(&self, path: &Utf8Path) -> Result<Utf8PathBuf> {
+        Ok(self.expand(path.as_str())?.into())
+    }
+
+    /// Expands the values of the env var, keys are left alone
+    pub fn expand_var(&self, var: EnvVar) -> Result<EnvVar> {
+        Ok(match var {
+            EnvVar::KeyValue(key, value) => EnvVar::KeyValue(key, self.expand(&value)?),
+            EnvVar::PassOr(key, default) => EnvVar::PassOr(key, self.expand(&default)?),
+            EnvVar::Append {
+                key,
+                value,
+                separator,
+            } => EnvVar::Append {
+                key,
+                value: self.expand(&value)?,
+                separator,
+            },
+            EnvVar::Prepend {
+                key,
+                value,
+                separator,
+            } => EnvVar::Prepend {
+                key,
+                value: self.expand(&value)?,
+                separator,
+            },
+            var => var,
+        })
+    }
+
+    /// `seen` holds the env vars being expanded right now, to catch ones which refer to themselves
+    fn expand_with(&self, value: &str, seen: &mut Vec<&'a str>) -> Result<String> {
+        let mut out = String::new();
+        let mut rest = value;
+
+        while let Some(idx) = rest.find('$') {
+            out.push_str(&rest[..idx]);
+            rest = &rest[idx..];
+
+            if let Some(after) = rest.strip_prefix("$$") {
+                out.push('$');
+                rest = after;
+                continue;
+            }
+
+            let after = match rest.strip_prefix("${") {
+                Some(after) => after,
+                None => {
+                    out.push('$');
+                    rest = &rest[1..];
+                    continue;
+                }
+            };
+
+            let end = after
+                .find('}')
+                .ok_or_else(|| CellarError::UnclosedVariable(value.to_string()))?;
+            out.push_str(&self.lookup(&after[..end], seen)?);
+            rest = &after[end + 1..];
+        }
+
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    fn lookup(&self, name: &str, seen: &mut Vec<&'a str>) -> Result<String> {
+        if let Some(value) = self.builtins.get(name) {
+            return Ok(value.clone());
+        }
+
+        let (name, value) = self
+            .vars
+            .get_key_value(name)
+            .ok_or_else(|| CellarError::UndefinedVariable(name.to_string()))?;
+        if seen.contains(name) {
+            return Err(CellarError::RecursiveVariable(name.to_string()));
+        }
+
+        seen.push(name);
+        let value = self.expand_with(value, seen)?;
+        seen.pop();
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_value(key: &str, value: &str) -> EnvVar {
+        EnvVar::KeyValue(key.into(), value.into())
+    }
+
+    fn expander(vars: &[EnvVar]) -> Expander<'_> {
+        Expander::new(Utf8Path::new("/nonexistent/game"), vars).unwrap()
+    }
+
+    #[test]
+    fn expands_builtins() {
+        let expander = expander(&[]);
+        let uid = std::fs::metadata("/proc/self").unwrap().uid();
+
+        assert_eq!(
+            expander.expand("${CELLAR}/drive_c").unwrap(),
+            "/nonexistent/game/drive_c"
+        );
+        assert_eq!(expander.expand("${CELLAR_NAME}").unwrap(), "game");
+        assert_eq!(expander.expand("${UID}").unwrap(), uid.to_string());
+        if let Ok(home) = std::env::var("HOME") {
+            assert_eq!(expander.expand("${HOME}/x").unwrap(), format!("{}/x", home));
+        }
+    }
+
+    #[test]
+    fn escapes_dollars() {
+        let expander = expander(&[]);
+
+        assert_eq!(expander.expand("$${CELLAR}").unwrap(), "${CELLAR}");
+        assert_eq!(expander.expand("$$$$").unwrap(), "$$");
+        // Only `${` starts a name
+        assert_eq!(expander.expand("$HOME costs 5$").unwrap(), "$HOME costs 5$");
+    }
+
+    #[test]
+    fn rejects_unknown_and_unclosed() {
+        let expander = expander(&[]);
+
+        assert!(matches!(
+            expander.expand("${NOPE}"),
+            Err(CellarError::UndefinedVariable(x)) if x == "NOPE"
+        ));
+        assert!(matches!(
+            expander.expand("a ${CELLAR"),
+            Err(CellarError::UnclosedVariable(x)) if x == "a ${CELLAR"
+        ));
+    }
+
+    #[test]
+    fn expands_cellar_vars() {
+        let vars = [
+            key_value("GAME", "${CELLAR}/drive_c/${DIR}"),
+            key_value("DIR", "Game"),
+            key_value("HOME", "/ignored"),
+            EnvVar::append("PATH", "${GAME}/bin", ":"),
+        ];
+        let expander = expander(&vars);
+
+        assert_eq!(
+            expander.expand("${GAME}").unwrap(),
+            "/nonexistent/game/drive_c/Game"
+        );
+        // Built in values come first
+        assert_ne!(expander.expand("${HOME}").ok().as_deref(), Some("/ignored"));
+        assert_eq!(
+            expander.expand_var(vars[3].clone()).unwrap(),
+            EnvVar::append("PATH", "/nonexistent/game/drive_c/Game/bin", ":")
+        );
+    }
+
+    #[test]
+    fn detects_recursion() {
+        let vars = [
+            key_value("A", "${B}"),
+            key_value("B", "x${A}"),
+            key_value("SELF", "${SELF}"),
+            key_value("TWICE", "${DIR}${DIR}"),
+            key_value("DIR", "d"),
+        ];
+        let expander = expander(&vars);
+
+        assert!(matches!(
+            expander.expand("${A}"),
+            Err(CellarError::RecursiveVariable(x)) if x == "A"
+        ));
+        assert!(matches!(
+            expander.expand("${SELF}"),
+            Err(CellarError::RecursiveVariable(x)) if x == "SELF"
+        ));
+        assert_eq!(expander.expand("${TWICE}").unwrap(), "dd");
+    }
+}
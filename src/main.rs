@@ -1,14 +1,27 @@
 mod cellar;
+mod drives;
+mod env;
+mod expand;
+mod mapping;
 mod reaper;
 
-use crate::cellar::{WineCellar, WineSync};
+use crate::cellar::{
+    get_reaper_path, Backend, DisplayMode, PrefixMode, WineCellar, WineSync, REAPER_SANDBOX_PATH,
+};
+use crate::drives::{DriveMapping, DriveType};
+use crate::env::EnvMap;
+use crate::mapping::{PathMapping, WinTarget};
 use crate::reaper::ReaperCommand;
 
 use std::collections::VecDeque;
-use std::process::Stdio;
+use std::fmt::Display;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
 
 use camino::Utf8PathBuf;
-use clap::{App, AppSettings, Arg};
+use cellar_sandbox::{firejail, render, status};
+use cellar_sandbox::{EnvVar, FirejailProfile, Rlimit, SandboxHandle, SandboxInfo, Severity};
+use clap::{App, AppSettings, Arg, ArgMatches};
 use flexi_logger::Logger;
 use log::{error, info, warn};
 
@@ -27,16 +40,124 @@ fn app<'a>() -> App<'a> {
                 .about("Creates the cellar if it does not exist")
                 .short('c'),
         )
+        .arg(
+            Arg::new("dry-run")
+                .about("Prints what would be run in the sandbox instead of running it")
+                .long("dry-run"),
+        )
+        .arg(
+            Arg::new("check")
+                .about("Checks the sandbox for problems instead of running the command")
+                .long("check"),
+        )
         .subcommand(
-            App::new("set-env")
-                .about("Sets an environmental variable")
-                .arg(Arg::new("key").required(true).takes_value(true))
-                .arg(Arg::new("value").required(true).takes_value(true)),
+            App::new("env")
+                .about("Manages the environmental variables of programs in the sandbox")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .arg(
+                    Arg::new("profile")
+                        .about("Works on the env vars of a profile instead")
+                        .short('p')
+                        .long("profile")
+                        .takes_value(true),
+                )
+                .subcommand(
+                    App::new("set")
+                        .about("Sets an environmental variable to a value")
+                        .arg(Arg::new("key").required(true))
+                        .arg(
+                            Arg::new("value")
+                                .about(
+                                    "Can use ${CELLAR}, ${HOME} or other env vars, $$ is a plain $",
+                                )
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("append")
+                                .about("Adds the value onto the end, with this separator")
+                                .long("append")
+                                .takes_value(true)
+                                .conflicts_with("prepend"),
+                        )
+                        .arg(
+                            Arg::new("prepend")
+                                .about("Adds the value onto the start, with this separator")
+                                .long("prepend")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    App::new("pass")
+                        .about("Passes an environmental variable through from the host")
+                        .arg(Arg::new("key").required(true))
+                        .arg(
+                            Arg::new("or")
+                                .about("The value to use if it isn't set on the host")
+                                .long("or")
+                                .takes_value(true)
+                                .conflicts_with("if-set"),
+                        )
+                        .arg(
+                            Arg::new("if-set")
+                                .about("Leaves it alone if it isn't set on the host")
+                                .long("if-set"),
+                        ),
+                )
+                .subcommand(
+                    App::new("unset")
+                        .about("Removes an environmental variable from the cellar")
+                        .arg(Arg::new("key").required(true)),
+                )
+                .subcommand(
+                    App::new("get")
+                        .about("Shows an environmental variable")
+                        .arg(Arg::new("key").required(true)),
+                )
+                .subcommand(App::new("list").about("Lists the environmental variables"))
+                .subcommand(
+                    App::new("import")
+                        .about("Sets the environmental variables from a .env file")
+                        .arg(Arg::new("file").required(true)),
+                )
+                .subcommand(
+                    App::new("export")
+                        .about("Writes the environmental variables as a .env file")
+                        .arg(Arg::new("file").about("Where to write to, otherwise stdout")),
+                )
+                .subcommand(App::new("profiles").about("Lists the env profiles"))
+                .subcommand(
+                    App::new("enable")
+                        .about("Uses an env profile for every launch")
+                        .arg(Arg::new("name").required(true)),
+                )
+                .subcommand(
+                    App::new("disable")
+                        .about("Only uses an env profile when it is asked for")
+                        .arg(Arg::new("name").required(true)),
+                )
+                .subcommand(
+                    App::new("remove-profile")
+                        .about("Removes an env profile from the cellar")
+                        .arg(Arg::new("name").required(true)),
+                )
+                .subcommand(
+                    App::new("effective")
+                        .about("Shows the env vars a launch with these profiles ends up with")
+                        .arg(Arg::new("profiles").multiple_values(true)),
+                ),
         )
         .subcommand(App::new("shell").about("Starts a new shell in the sandbox"))
         .subcommand(
             App::new("exec")
                 .about("Allows you to run programs")
+                .arg(
+                    Arg::new("profile")
+                        .about("Uses the env vars of a profile")
+                        .short('p')
+                        .long("profile")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
                 .arg(
                     Arg::new("executable")
                         .required(true)
@@ -52,17 +173,134 @@ fn app<'a>() -> App<'a> {
                         .about("All arguments to be passed to the executable"),
                 ),
         )
+        .subcommand(
+            App::new("map")
+                .about("Makes a host folder show up at a windows path inside the prefix")
+                .arg(
+                    Arg::new("host")
+                        .required(true)
+                        .takes_value(true)
+                        .about("The folder on the host"),
+                )
+                .arg(
+                    Arg::new("windows")
+                        .required(true)
+                        .takes_value(true)
+                        .about("A windows path like C:\\Games, or a profile folder like Documents"),
+                )
+                .arg(
+                    Arg::new("read-only")
+                        .long("read-only")
+                        .about("Mounts the folder as read only"),
+                ),
+        )
+        .subcommand(
+            App::new("unmap")
+                .about("Removes a folder mapping")
+                .arg(Arg::new("windows").required(true).takes_value(true)),
+        )
+        .subcommand(App::new("list-maps").about("Lists folder mappings"))
+        .subcommand(App::new("list-drives").about("Lists the drive letters of the prefix"))
+        .subcommand(
+            App::new("add-drive")
+                .about("Adds a drive letter backed by a folder on the host")
+                .arg(Arg::new("letter").required(true).takes_value(true))
+                .arg(
+                    Arg::new("host")
+                        .required(true)
+                        .takes_value(true)
+                        .about("The folder or mount point on the host"),
+                )
+                .arg(
+                    Arg::new("type")
+                        .long("type")
+                        .takes_value(true)
+                        .possible_values(&["hd", "network", "cdrom", "floppy"]),
+                ),
+        )
+        .subcommand(
+            App::new("remove-drive")
+                .about("Removes a drive letter, such as z: which exposes the whole sandbox")
+                .arg(Arg::new("letter").required(true).takes_value(true)),
+        )
+        .subcommand(
+            App::new("set-drive-type")
+                .about("Sets what kind of drive wine reports a drive letter as")
+                .arg(Arg::new("letter").required(true).takes_value(true))
+                .arg(
+                    Arg::new("type")
+                        .required(true)
+                        .possible_values(&["hd", "network", "cdrom", "floppy"]),
+                ),
+        )
+        .subcommand(
+            App::new("enter")
+                .about("Joins the sandbox which is running in the cellar")
+                .arg(Arg::new("program").takes_value(true)),
+        )
+        .subcommand(
+            App::new("import-profile")
+                .about("Uses a firejail profile for the sandbox")
+                .arg(Arg::new("profile").required(true)),
+        )
+        .subcommand(
+            App::new("export-oci")
+                .about("Writes the sandbox as an OCI bundle, for crun or runc")
+                .arg(Arg::new("bundle").required(true))
+                .arg(Arg::new("program").multiple_values(true)),
+        )
+        .subcommand(
+            App::new("set-env")
+                .about("Sets an environmental variable, the same as \"env set\"")
+                .arg(Arg::new("key").required(true))
+                .arg(Arg::new("value").required(true)),
+        )
+        .subcommand(
+            App::new("list-env")
+                .about("Lists the environmental variables, the same as \"env list\""),
+        )
+        .subcommand(App::new("ps").about("Lists the processes in the running sandbox"))
+        .subcommand(App::new("stop").about("Stops the running sandbox"))
         .subcommand(App::new("kill"))
-        .subcommand(App::new("list-env").about("Lists environmental variables"))
         .subcommand(App::new("cfg-list").about("Lists settings in the sandbox"))
         .subcommand(
             App::new("cfg-set")
                 .about("Set settings")
-                .arg(Arg::new("key").required(true).possible_value("sync"))
+                .arg(Arg::new("key").required(true).possible_values(&[
+                    "sync",
+                    "prefix-mode",
+                    "tmp-size",
+                    "display",
+                    "backend",
+                    "landlock",
+                    "systemd-scope",
+                    "memory-max",
+                    "cpu-quota",
+                    "io-weight",
+                    "rlimit",
+                ]))
                 .arg(Arg::new("value").required(true)),
         )
 }
 
+/// The value of a cfg-set key which limits something, where "none" removes the limit
+fn limit<T>(args: &ArgMatches) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match args.value_of("value") {
+        Some("none") => None,
+        _ => Some(args.value_of_t_or_exit("value")),
+    }
+}
+
+fn warn_native_scope(cellar: &WineCellar) {
+    if cellar.config.backend == Backend::NATIVE && cellar.config.systemd_scope {
+        warn!("The native backend can't start in a systemd scope, turn off \"systemd-scope\" to use it");
+    }
+}
+
 fn main() -> cellar::Result<()> {
     Logger::try_with_str("debug").unwrap().start().unwrap();
 
@@ -77,6 +315,23 @@ fn main() -> cellar::Result<()> {
         }
     };
 
+    if matches.is_present("check") {
+        let diagnostics = cellar.check()?;
+        diagnostics.iter().for_each(|x| match x.severity() {
+            Severity::Warning => warn!("{}", x),
+            Severity::Error => error!("{}", x),
+        });
+
+        info!("Found {} problem(s)", diagnostics.len());
+        if diagnostics.iter().any(|x| x.severity() == Severity::Error) {
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
+    let dry_run = matches.is_present("dry-run");
+
     match matches.subcommand() {
         Some(("cfg-list", _)) => {
             let serialized = serde_json::to_value(cellar.config).unwrap();
@@ -97,28 +352,392 @@ fn main() -> cellar::Result<()> {
                 cellar.config.sync = sync_type;
                 cellar.save_config().unwrap();
             }
+            "prefix-mode" => {
+                let mode: PrefixMode = args.value_of_t_or_exit("value");
+                info!("Setting \"prefix-mode\" to \"{:#?}\"", mode);
+
+                cellar.config.prefix_mode = mode;
+                cellar.save_config()?;
+            }
+            "backend" => {
+                let backend: Backend = args.value_of_t_or_exit("value");
+                info!("Setting \"backend\" to \"{:#?}\"", backend);
+
+                cellar.config.backend = backend;
+                cellar.save_config()?;
+                warn_native_scope(&cellar);
+            }
+            "display" => {
+                let mode: DisplayMode = args.value_of_t_or_exit("value");
+                info!("Setting \"display\" to \"{:#?}\"", mode);
+
+                cellar.config.display = mode;
+                cellar.save_config()?;
+            }
+            "landlock" => {
+                let landlock: bool = args.value_of_t_or_exit("value");
+                info!("Setting \"landlock\" to \"{}\"", landlock);
+
+                cellar.config.landlock = landlock;
+                cellar.save_config()?;
+            }
+            "tmp-size" => {
+                let size = limit::<u64>(args);
+                info!("Setting \"tmp-size\" to \"{:?}\"", size);
+
+                cellar.config.tmp_size = size;
+                cellar.save_config()?;
+            }
+            "systemd-scope" => {
+                let scope: bool = args.value_of_t_or_exit("value");
+                info!("Setting \"systemd-scope\" to \"{}\"", scope);
+
+                cellar.config.systemd_scope = scope;
+                cellar.save_config()?;
+                warn_native_scope(&cellar);
+            }
+            "memory-max" => {
+                let bytes = limit::<u64>(args);
+                info!("Setting \"memory-max\" to \"{:?}\"", bytes);
+
+                cellar.config.memory_max = bytes;
+                cellar.save_config()?;
+            }
+            "cpu-quota" => {
+                let percent = limit::<u32>(args);
+                info!("Setting \"cpu-quota\" to \"{:?}\"", percent);
+
+                cellar.config.cpu_quota = percent;
+                cellar.save_config()?;
+            }
+            "io-weight" => {
+                let weight = limit::<u16>(args);
+                info!("Setting \"io-weight\" to \"{:?}\"", weight);
+
+                cellar.config.io_weight = weight;
+                cellar.save_config()?;
+            }
+            // Takes limits like nofile=4096, nofile=none removes the limit
+            "rlimit" => {
+                let value: String = args.value_of_t_or_exit("value");
+
+                match value.split_once('=') {
+                    Some((resource, "none")) => {
+                        let name = format!("RLIMIT_{}", resource.to_ascii_uppercase());
+                        info!("Removing the limit on \"{}\"", resource);
+
+                        cellar.config.rlimits.retain(|x| x.name() != name);
+                    }
+                    _ => {
+                        let limit: Rlimit = args.value_of_t_or_exit("value");
+                        info!("Setting \"rlimit\" to \"{:?}\"", limit);
+
+                        cellar.config.rlimits.retain(|x| !x.same_resource(&limit));
+                        cellar.config.rlimits.push(limit);
+                    }
+                }
+                cellar.save_config()?;
+            }
             unknown => error!("Unknown key \"{}\"", unknown),
         },
 
+        // Kept from before the env subcommands
         Some(("set-env", args)) => {
             let key: String = args.value_of_t_or_exit("key");
             let value: String = args.value_of_t_or_exit("value");
 
-            info!("Set {} to {}", key, value);
-            cellar.set_env_var((key, value));
+            let var = EnvVar::KeyValue(key, value);
+            info!("Setting {}", var);
+            cellar.env_mut(None).set(var);
             cellar.save_config()?;
         }
+        Some(("list-env", _)) => cellar
+            .env(None)?
+            .as_slice()
+            .iter()
+            .for_each(|x| info!("{}", x)),
+
+        Some(("env", args)) => {
+            let profile = args.value_of("profile");
+
+            match args.subcommand() {
+                Some(("set", args)) => {
+                    let key: String = args.value_of_t_or_exit("key");
+                    let value: String = args.value_of_t_or_exit("value");
+
+                    let var = match (args.value_of("append"), args.value_of("prepend")) {
+                        (Some(separator), _) => EnvVar::append(key, value, separator),
+                        (_, Some(separator)) => EnvVar::prepend(key, value, separator),
+                        _ => EnvVar::KeyValue(key, value),
+                    };
+
+                    info!("Setting {}", var);
+                    cellar.env_mut(profile).set(var);
+                    cellar.save_config()?;
+                }
+
+                Some(("pass", args)) => {
+                    let key: String = args.value_of_t_or_exit("key");
+
+                    let var = match args.value_of("or") {
+                        Some(default) => EnvVar::PassOr(key, default.into()),
+                        None if args.is_present("if-set") => EnvVar::PassIfSet(key),
+                        None => EnvVar::Pass(key),
+                    };
+
+                    info!("Setting {}", var);
+                    cellar.env_mut(profile).set(var);
+                    cellar.save_config()?;
+                }
+
+                Some(("unset", args)) => {
+                    let key: String = args.value_of_t_or_exit("key");
+
+                    match cellar.env_mut(profile).unset(&key) {
+                        Some(var) => info!("Removed {}", var),
+                        None => warn!("{} is not set", key),
+                    }
+                    cellar.save_config()?;
+                }
+
+                Some(("get", args)) => {
+                    let key: String = args.value_of_t_or_exit("key");
+
+                    match cellar.env(profile)?.get(&key) {
+                        Some(var) => println!("{}", var),
+                        None => warn!("{} is not set", key),
+                    }
+                }
+
+                Some(("list", _)) => cellar
+                    .env(profile)?
+                    .as_slice()
+                    .iter()
+                    .for_each(|x| info!("{}", x)),
+
+                Some(("import", args)) => {
+                    let file = args.value_of_t_or_exit::<Utf8PathBuf>("file");
+                    let vars = EnvMap::parse_dotenv(&std::fs::read_to_string(&file)?)?;
+
+                    info!("Importing {} env vars from {}", vars.len(), file);
+                    vars.into_iter().for_each(|x| {
+                        cellar.env_mut(profile).set(x);
+                    });
+                    cellar.save_config()?;
+                }
+
+                Some(("export", args)) => match args.value_of("file") {
+                    Some(file) => std::fs::write(file, cellar.env(profile)?.to_dotenv())?,
+                    None => print!("{}", cellar.env(profile)?.to_dotenv()),
+                },
 
-        Some(("list-env", _)) => cellar.get_env_vars().iter().for_each(|e| info!("{:?}", e)),
+                Some(("profiles", _)) => {
+                    for (name, (env, global)) in cellar.env_profiles()? {
+                        let enabled = cellar.default_profiles().contains(&name);
+                        info!(
+                            "{} ({} env vars, global: {}, enabled: {})",
+                            name,
+                            env.as_slice().len(),
+                            global,
+                            enabled
+                        );
+                    }
+                }
+
+                Some(("enable", args)) => {
+                    let name: String = args.value_of_t_or_exit("name");
+
+                    info!("Using env profile {} for every launch", name);
+                    cellar.set_profile_default(&name, true)?;
+                    cellar.save_config()?;
+                }
+
+                Some(("disable", args)) => {
+                    let name: String = args.value_of_t_or_exit("name");
+
+                    info!("Only using env profile {} when asked for", name);
+                    cellar.set_profile_default(&name, false)?;
+                    cellar.save_config()?;
+                }
+
+                Some(("remove-profile", args)) => {
+                    let name: String = args.value_of_t_or_exit("name");
+
+                    match cellar.remove_env_profile(&name) {
+                        Some(_) => info!("Removed env profile {}", name),
+                        None => warn!("The cellar has no env profile named {}", name),
+                    }
+                    cellar.save_config()?;
+                }
+
+                Some(("effective", args)) => {
+                    let profiles = args
+                        .values_of("profiles")
+                        .into_iter()
+                        .flatten()
+                        .map(String::from)
+                        .collect::<Vec<_>>();
+
+                    cellar
+                        .effective_env(&profiles)?
+                        .iter()
+                        .for_each(|x| println!("{}", x));
+                }
+
+                _ => unreachable!("clap requires a subcommand"),
+            }
+        }
+
+        Some(("map", args)) => {
+            let host = args.value_of_t_or_exit::<Utf8PathBuf>("host");
+            let target = args.value_of_t_or_exit::<WinTarget>("windows");
+
+            let host = std::fs::canonicalize(host)?;
+            let host = Utf8PathBuf::try_from(host).map_err(|x| x.from_path_error())?;
+
+            info!("Mapping {} to {}", host, target);
+            cellar.add_mapping(PathMapping {
+                host,
+                target,
+                read_only: args.is_present("read-only"),
+            });
+            cellar.save_config()?;
+        }
+
+        Some(("unmap", args)) => {
+            let target = args.value_of_t_or_exit::<WinTarget>("windows");
+
+            match cellar.remove_mapping(&target) {
+                Some(mapping) => info!("Removed mapping of {} to {}", mapping.host, target),
+                None => warn!("Nothing is mapped to {}", target),
+            }
+            cellar.save_config()?;
+        }
+
+        Some(("list-maps", _)) => cellar
+            .get_mappings()
+            .iter()
+            .for_each(|x| info!("{} -> {} (read only: {})", x.host, x.target, x.read_only)),
+
+        Some(("list-drives", _)) => {
+            for drive in drives::list_drives(cellar.path())? {
+                match drive.kind {
+                    Some(kind) => info!("{}: -> {} ({:?})", drive.letter, drive.target, kind),
+                    None => info!("{}: -> {}", drive.letter, drive.target),
+                }
+            }
+        }
+
+        Some(("add-drive", args)) => {
+            let letter = drives::drive_letter(args.value_of_t_or_exit::<String>("letter"))?;
+            let host = std::fs::canonicalize(args.value_of_t_or_exit::<Utf8PathBuf>("host"))?;
+            let host = Utf8PathBuf::try_from(host).map_err(|x| x.from_path_error())?;
+
+            info!("Adding drive {}: backed by {}", letter, host);
+            cellar.add_drive(DriveMapping { letter, host })?;
+            cellar.save_config()?;
+
+            if args.is_present("type") {
+                let kind: DriveType = args.value_of_t_or_exit("type");
+                cellar.set_drive_type(letter, kind)?;
+            }
+        }
+
+        Some(("remove-drive", args)) => {
+            let letter = drives::drive_letter(args.value_of_t_or_exit::<String>("letter"))?;
+
+            match cellar.remove_drive(letter)? {
+                true => info!("Removed drive {}:", letter),
+                false => warn!("Drive {}: does not exist", letter),
+            }
+            cellar.save_config()?;
+        }
+
+        Some(("set-drive-type", args)) => {
+            let letter = drives::drive_letter(args.value_of_t_or_exit::<String>("letter"))?;
+            let kind: DriveType = args.value_of_t_or_exit("type");
+
+            info!("Setting type of drive {}: to {:?}", letter, kind);
+            cellar.set_drive_type(letter, kind)?;
+        }
+
+        Some(("shell", _)) if dry_run => match cellar.config.backend {
+            // The native backend sets up what bwrap would, so this shows what it does as well
+            Backend::BUBBLEWRAP | Backend::NATIVE => {
+                println!("{}", cellar.bwrap_render(["/usr/bin/bash"])?)
+            }
+            Backend::FIREJAIL => {
+                let mut cmd = cellar.firejail_run()?;
+                cmd.arg("/usr/bin/bash");
+                println!("{}", render::render(&cmd));
+            }
+            Backend::LANDLOCK => {
+                println!(
+                    "{}",
+                    render::render(&cellar.scoped(cellar.landlock_run("/usr/bin/bash", None)?))
+                );
+                if let Some(ruleset) = cellar.landlock_ruleset()? {
+                    println!("\nlandlock:\n{}", serde_json::to_string_pretty(&ruleset)?);
+                }
+            }
+        },
+
+        Some(("shell", _)) if cellar.config.backend == Backend::FIREJAIL => {
+            // Joining keeps everything in one sandbox, like the wineserver
+            let mut cmd = match cellar.firejail_session()? {
+                Some(sandbox) => {
+                    info!("Joining firejail sandbox with pid {}", sandbox.pid);
+                    firejail::join(&cellar.firejail_name())
+                }
+                None => {
+                    info!("Starting shell with firejail sandbox");
+                    cellar.check_scope()?;
+                    cellar.prepare_prefix()?;
+                    cellar.firejail_run()?
+                }
+            };
+
+            cmd.arg("/usr/bin/bash").status()?;
+        }
+
+        Some(("shell", _)) if cellar.config.backend == Backend::LANDLOCK => {
+            info!("Starting shell with landlock rules");
+            cellar.check_scope()?;
+            cellar.prepare_prefix()?;
+
+            let display = cellar.start_display()?;
+            let mut cmd = cellar.scoped(cellar.landlock_run("/usr/bin/bash", display.as_ref())?);
+            cellar.landlock_self()?;
+            cmd.status()?;
+        }
+
+        Some(("shell", _)) if cellar.config.backend == Backend::NATIVE => {
+            info!("Starting shell with native sandbox");
+
+            let display = cellar.start_display()?;
+            let (cmd, status) = cellar.native_session(display.as_ref(), "/usr/bin/bash")?;
+            SandboxHandle::spawn(cmd, status)?.wait()?;
+        }
 
         Some(("shell", _)) => {
             info!("Starting shell with bubblewrap sandbox");
 
-            cellar.bwrap_run().arg("/usr/bin/bash").status().unwrap();
+            let display = cellar.start_display()?;
+            cellar
+                .bwrap_run(display.as_ref())?
+                .arg("/usr/bin/bash")
+                .status()
+                .unwrap();
         }
 
         Some(("exec", args)) => {
             let exec_path = args.value_of_t_or_exit::<Utf8PathBuf>("executable");
+            let profiles = args
+                .values_of("profile")
+                .into_iter()
+                .flatten()
+                .map(String::from)
+                .collect::<Vec<_>>();
 
             // TODO Add wine version information
             info!("Using wine version {}", "todo");
@@ -141,32 +760,227 @@ fn main() -> cellar::Result<()> {
             // start
             exec_args.push_front(exec_path.into_string());
 
-            let mut child = cellar
-                .bwrap_run()
-                .arg("/tmp/reaper")
-                .stdin(Stdio::piped())
-                .spawn()
-                .unwrap();
-
-            info!("Starting reaper in jail");
             let start_cmd = ReaperCommand::Execute {
                 exec: "/usr/bin/wine".into(),
                 args: exec_args.into_iter().collect(),
-                env: cellar.get_env_vars().clone(),
+                // Anything passed through has to come from here, the reaper only sees the sandbox
+                env: cellar.captured_env(&profiles)?,
+                landlock: cellar.landlock_ruleset()?,
+            };
+
+            if dry_run {
+                match cellar.config.backend {
+                    Backend::LANDLOCK => {
+                        let cmd = cellar.scoped(cellar.landlock_run(get_reaper_path()?, None)?);
+                        println!("{}", render::render(&cmd));
+                    }
+                    Backend::FIREJAIL => {
+                        let mut cmd = cellar.firejail_run()?;
+                        cmd.arg(get_reaper_path()?);
+                        println!("{}", render::render(&cmd));
+                    }
+                    _ => println!("{}", cellar.bwrap_render([REAPER_SANDBOX_PATH])?),
+                }
+
+                println!("\nenv:");
+                cellar
+                    .effective_env(&profiles)?
+                    .iter()
+                    .for_each(|x| println!("{}", x));
+
+                println!("\nreaper:\n{}", serde_json::to_string_pretty(&start_cmd)?);
+                return Ok(());
+            }
+
+            // firejail starts the nested server itself
+            let display = match cellar.config.backend {
+                Backend::FIREJAIL => None,
+                _ => cellar.start_display()?,
+            };
+
+            let code = if cellar.config.backend == Backend::FIREJAIL {
+                cellar.check_scope()?;
+                cellar.prepare_prefix()?;
+                // Named after the cellar, so `ps` and `stop` find it through firejail
+                let mut child = cellar
+                    .firejail_run()?
+                    .arg(get_reaper_path()?)
+                    .stdin(Stdio::piped())
+                    .spawn()?;
+
+                info!("Starting reaper with firejail sandbox");
+                start_cmd.dispatch(child.stdin.take().unwrap()).unwrap();
+
+                child.wait()?.code().unwrap_or(1)
+            } else if cellar.config.backend == Backend::LANDLOCK {
+                cellar.check_scope()?;
+                cellar.prepare_prefix()?;
+                let mut child = cellar
+                    .scoped(cellar.landlock_run(get_reaper_path()?, display.as_ref())?)
+                    .stdin(Stdio::piped())
+                    .spawn()?;
+                info!("Reaper running as pid {}", child.id());
+                // Lets `ps` and `stop` find it, the same as a bwrap sandbox
+                cellar.record_session(&SandboxInfo::process(child.id() as i32))?;
+
+                info!("Starting reaper with landlock rules");
+                start_cmd.dispatch(child.stdin.take().unwrap()).unwrap();
+
+                child.wait()?.code().unwrap_or(1)
+            } else {
+                let (mut cmd, status) = match cellar.config.backend {
+                    Backend::NATIVE => {
+                        cellar.native_session(display.as_ref(), REAPER_SANDBOX_PATH)?
+                    }
+                    _ => {
+                        let (mut cmd, status) = cellar.bwrap_session(display.as_ref())?;
+                        cmd.arg(REAPER_SANDBOX_PATH);
+                        (cmd, status)
+                    }
+                };
+                cmd.stdin(Stdio::piped());
+
+                let mut sandbox = SandboxHandle::spawn(cmd, status)?;
+                info!("Sandbox running as pid {}", sandbox.info.child_pid);
+                cellar.record_session(&sandbox.info)?;
+
+                info!("Starting reaper in jail");
+                let child_stdin = sandbox.child.stdin.take().unwrap();
+                start_cmd.dispatch(&child_stdin).unwrap();
+
+                drop(child_stdin);
+
+                sandbox.wait()?
             };
+            cellar.clear_session()?;
+            drop(display);
+            info!("Reaper dead with exit code {}, quitting", code);
+
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
 
-            let child_stdin = child.stdin.as_mut().unwrap();
-            start_cmd.dispatch(&*child_stdin).unwrap();
+        Some(("enter", args)) if cellar.config.backend == Backend::FIREJAIL => {
+            match cellar.firejail_session()? {
+                Some(sandbox) => {
+                    let program = args.value_of("program").unwrap_or("/usr/bin/bash");
+                    info!("Entering sandbox with pid {}", sandbox.pid);
 
-            drop(child_stdin);
+                    firejail::join(&cellar.firejail_name())
+                        .arg(program)
+                        .status()?;
+                }
+                None => error!("No sandbox is running in this cellar"),
+            }
+        }
 
-            child.wait().unwrap();
-            info!("Reaper dead, quitting");
+        // There is nothing to join, but a program with the same rules gets to see the same things
+        Some(("enter", args)) if cellar.config.backend == Backend::LANDLOCK => {
+            let program = args.value_of("program").unwrap_or("/usr/bin/bash");
+            let mut cmd = cellar.landlock_run(program, None)?;
+            cellar.landlock_self()?;
+            cmd.status()?;
         }
 
+        Some(("enter", args)) => match cellar.session()? {
+            Some(info) => {
+                let program = args.value_of("program").unwrap_or("/usr/bin/bash");
+                info!("Entering sandbox with pid {}", info.child_pid);
+
+                Command::new("nsenter")
+                    .arg("--target")
+                    .arg(info.child_pid.to_string())
+                    .args(["--all", "--preserve-credentials", "--root", "--wd"])
+                    .arg("--")
+                    .arg(program)
+                    .env_clear()
+                    .env("HOME", "/home")
+                    .env("WINEPREFIX", "/wineprefix")
+                    .env("PATH", "/usr/bin:/bin")
+                    .status()?;
+            }
+            None => error!("No sandbox is running in this cellar"),
+        },
+
+        Some(("import-profile", args)) => {
+            let path = std::fs::canonicalize(args.value_of_t_or_exit::<Utf8PathBuf>("profile"))?;
+            let path = Utf8PathBuf::try_from(path).map_err(|x| x.from_path_error())?;
+            let profile = FirejailProfile::load(&path)?;
+
+            for unsupported in &profile.unsupported {
+                warn!("Ignoring {}", unsupported);
+            }
+            for untranslated in cellar.untranslated_profile(&profile)? {
+                warn!("Not used with bubblewrap {}", untranslated);
+            }
+
+            info!(
+                "Using firejail profile {} with {} entries",
+                path,
+                profile.entries.len()
+            );
+            cellar.config.firejail_profile = Some(path);
+            cellar.save_config()?;
+        }
+
+        Some(("export-oci", args)) => {
+            let path = args.value_of_t_or_exit::<Utf8PathBuf>("bundle");
+            let program = match args.values_of("program") {
+                Some(program) => program.map(String::from).collect(),
+                None => vec!["/usr/bin/bash".to_string()],
+            };
+
+            let bundle = cellar.oci_bundle(&path, program)?;
+            for untranslated in &bundle.untranslated {
+                warn!("Not in the OCI bundle: {}", untranslated);
+            }
+            if cellar.config.landlock {
+                warn!("Not in the OCI bundle: the landlock rules");
+            }
+
+            if dry_run {
+                println!("{}", serde_json::to_string_pretty(&bundle.spec)?);
+                return Ok(());
+            }
+
+            bundle.write()?;
+            info!("Wrote OCI bundle to {}", bundle.path().display());
+        }
+
+        Some(("ps", _)) => {
+            match cellar.sandbox_pid()? {
+                Some(pid) => status::processes(pid)?
+                    .iter()
+                    .for_each(|x| println!("{:>8} {}", x.pid, x.cmdline)),
+                None => error!("No sandbox is running in this cellar"),
+            }
+
+            if let Some(stats) = cellar.scope_stats()? {
+                let show = |x: Option<u64>, unit: u64, suffix: &str| match x {
+                    Some(x) => format!("{:.1}{}", x as f64 / unit as f64, suffix),
+                    None => "-".to_string(),
+                };
+
+                println!(
+                    "\nmemory {}, cpu time {}, tasks {}, io read {}, io written {}",
+                    show(stats.memory, 1 << 20, " MiB"),
+                    show(stats.cpu_time, 1_000_000_000, "s"),
+                    show(stats.tasks, 1, ""),
+                    show(stats.io_read, 1 << 20, " MiB"),
+                    show(stats.io_written, 1 << 20, " MiB"),
+                );
+            }
+        }
+
+        Some(("stop", _)) => match cellar.stop()? {
+            true => info!("Stopped the sandbox"),
+            false => warn!("No sandbox is running in this cellar"),
+        },
+
         Some(("kill", _)) => {
             println!("Killing prefix at {:?}", cellar.path());
-            cellar.kill();
+            cellar.kill()?;
         }
 
         Some((name, _)) => error!("Unknown or unimplemented command {}", name),
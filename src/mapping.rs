@@ -0,0 +1,336 @@
+use std::str::FromStr;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::cellar::{CellarError, Result, SANDBOX_USER};
+
+/// Where the wine prefix is mounted inside of the sandbox
+pub const SANDBOX_PREFIX: &str = "/wineprefix";
+
+const USER_SHELL_FOLDERS_KEY: &str =
+    r"[Software\\Microsoft\\Windows\\CurrentVersion\\Explorer\\User Shell Folders]";
+
+/// A host folder which should show up somewhere inside of the prefix
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathMapping {
+    pub host: Utf8PathBuf,
+    pub target: WinTarget,
+
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WinTarget {
+    /// An absolute windows path, such as `C:\Games\Foo\Mods`
+    Path(String),
+
+    /// One of the folders of the user profile, resolved through the prefix registry
+    ShellFolder(ShellFolder),
+}
+
+impl std::fmt::Display for WinTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WinTarget::Path(path) => write!(f, "{}", path),
+            WinTarget::ShellFolder(folder) => write!(f, "{:?}", folder),
+        }
+    }
+}
+
+impl FromStr for WinTarget {
+    type Err = CellarError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(folder) = s.parse::<ShellFolder>() {
+            return Ok(WinTarget::ShellFolder(folder));
+        }
+
+        // Make sure the path is valid before we store it
+        WinPath::parse(s)?;
+        Ok(WinTarget::Path(s.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShellFolder {
+    Desktop,
+    Documents,
+    Downloads,
+    Music,
+    Pictures,
+    Videos,
+    Templates,
+}
+
+impl ShellFolder {
+    /// The name of the value under the "User Shell Folders" registry key
+    pub const fn registry_value(&self) -> &'static str {
+        match self {
+            Self::Desktop => "Desktop",
+            Self::Documents => "Personal",
+            Self::Downloads => "{374DE290-123F-4565-9164-39C4925E467B}",
+            Self::Music => "My Music",
+            Self::Pictures => "My Pictures",
+            Self::Videos => "My Videos",
+            Self::Templates => "Templates",
+        }
+    }
+
+    /// What wine uses when the registry has no entry for the folder
+    pub const fn default_path(&self) -> &'static str {
+        match self {
+            Self::Desktop => r"%USERPROFILE%\Desktop",
+            Self::Documents => r"%USERPROFILE%\Documents",
+            Self::Downloads => r"%USERPROFILE%\Downloads",
+            Self::Music => r"%USERPROFILE%\Music",
+            Self::Pictures => r"%USERPROFILE%\Pictures",
+            Self::Videos => r"%USERPROFILE%\Videos",
+            Self::Templates => r"%USERPROFILE%\Templates",
+        }
+    }
+}
+
+impl FromStr for ShellFolder {
+    type Err = CellarError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_ref() {
+            "desktop" => Ok(ShellFolder::Desktop),
+            "documents" | "personal" => Ok(ShellFolder::Documents),
+            "downloads" => Ok(ShellFolder::Downloads),
+            "music" => Ok(ShellFolder::Music),
+            "pictures" => Ok(ShellFolder::Pictures),
+            "videos" => Ok(ShellFolder::Videos),
+            "templates" => Ok(ShellFolder::Templates),
+            _ => Err(CellarError::InvalidWindowsPath(s.to_string())),
+        }
+    }
+}
+
+/// A parsed, absolute windows path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WinPath {
+    pub drive: char,
+    pub components: Vec<String>,
+}
+
+impl WinPath {
+    pub fn parse<T: AsRef<str>>(path: T) -> Result<WinPath> {
+        let path = path.as_ref();
+        let mut chars = path.chars();
+
+        let drive = match (chars.next(), chars.next()) {
+            (Some(letter), Some(':')) if letter.is_ascii_alphabetic() => {
+                letter.to_ascii_lowercase()
+            }
+            _ => return Err(CellarError::InvalidWindowsPath(path.to_string())),
+        };
+
+        let components = chars
+            .as_str()
+            .split(['\\', '/'])
+            .filter(|x| !x.is_empty() && *x != ".")
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>();
+
+        if components.iter().any(|x| x == "..") {
+            return Err(CellarError::InvalidWindowsPath(path.to_string()));
+        }
+
+        Ok(WinPath { drive, components })
+    }
+
+    /// The location of this path relative to the root of the prefix
+    pub fn prefix_relative(&self) -> Result<Utf8PathBuf> {
+        let mut relative = match self.drive {
+            'c' => Utf8PathBuf::from("drive_c"),
+            other => return Err(CellarError::UnsupportedDrive(other)),
+        };
+
+        self.components.iter().for_each(|x| relative.push(x));
+
+        Ok(relative)
+    }
+}
+
+impl PathMapping {
+    /// Resolves where the mapping ends up, relative to the root of the prefix
+    pub fn resolve(&self, prefix: &Utf8Path) -> Result<Utf8PathBuf> {
+        let path = match &self.target {
+            WinTarget::Path(path) => WinPath::parse(path)?,
+            WinTarget::ShellFolder(folder) => WinPath::parse(shell_folder_path(prefix, *folder)?)?,
+        };
+
+        path.prefix_relative()
+    }
+
+    /// Where the mapping is mounted inside of the sandbox
+    pub fn sandbox_path(&self, prefix: &Utf8Path) -> Result<Utf8PathBuf> {
+        Ok(Utf8Path::new(SANDBOX_PREFIX).join(self.resolve(prefix)?))
+    }
+
+    /// Makes sure there is a real directory for the mapping to be mounted over.
+    ///
+    /// Wine likes to turn the profile folders into symlinks to the home directory of the user,
+    /// which do not exist within the sandbox, so those get replaced by an empty directory. Any
+    /// other symlink on the way is refused, as the directories would be created wherever it
+    /// points to on the host.
+    pub fn prepare(&self, prefix: &Utf8Path) -> Result<()> {
+        let relative = self.resolve(prefix)?;
+        let mut on_host = prefix.to_path_buf();
+        let mut components = relative.components().peekable();
+
+        while let Some(component) = components.next() {
+            on_host.push(component);
+            let last = components.peek().is_none();
+
+            match std::fs::symlink_metadata(&on_host) {
+                Ok(meta) if meta.is_symlink() && last => {
+                    debug!("replacing symlink {} with a directory", on_host);
+                    std::fs::remove_file(&on_host)?;
+                    std::fs::create_dir(&on_host)?;
+                }
+                Ok(meta) if meta.is_symlink() => return Err(CellarError::SymlinkInPrefix(on_host)),
+                Ok(meta) if meta.is_dir() => {}
+                // Fails for anything else which is in the way
+                Ok(_) => std::fs::create_dir(&on_host)?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    std::fs::create_dir(&on_host)?
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the windows path of a profile folder, as configured in the registry of the prefix
+pub fn shell_folder_path(prefix: &Utf8Path, folder: ShellFolder) -> Result<String> {
+    let configured = match std::fs::read_to_string(prefix.join("user.reg")) {
+        Ok(registry) => read_reg_value(&registry, USER_SHELL_FOLDERS_KEY, folder.registry_value()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+
+    let path = configured.unwrap_or_else(|| folder.default_path().to_string());
+    let profile = format!(r"C:\users\{}", profile_user(prefix)?);
+
+    Ok(path.replace("%USERPROFILE%", &profile))
+}
+
+/// Finds the name of the user profile which wine created in the prefix, the first one by name if
+/// there are several
+pub fn profile_user(prefix: &Utf8Path) -> Result<String> {
+    let users = prefix.join("drive_c").join("users");
+
+    // Prefixes from before the sandbox had its own user may still have the profile of the real one
+    if users.join(SANDBOX_USER).is_dir() {
+        return Ok(SANDBOX_USER.to_string());
+    }
+
+    let mut found = match users.read_dir_utf8() {
+        Ok(entries) => entries
+            .filter_map(|x| x.ok())
+            .map(|x| x.file_name().to_string())
+            .filter(|x| x != "Public")
+            .collect::<Vec<_>>(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+    found.sort();
+
+    found.into_iter().next().ok_or(CellarError::MissingProfile)
+}
+
+/// Reads a string value from a wine `.reg` file
+pub(crate) fn read_reg_value(registry: &str, key: &str, value: &str) -> Option<String> {
+    read_reg_section(registry, key)
+        .into_iter()
+        .find(|x| x.0 == value)
+        .map(|x| x.1)
+}
+
+/// Reads all of the string values under a key of a wine `.reg` file
+pub(crate) fn read_reg_section(registry: &str, key: &str) -> Vec<(String, String)> {
+    registry
+        .lines()
+        .skip_while(|x| !x.starts_with(key))
+        .skip(1)
+        .take_while(|x| !x.starts_with('['))
+        .filter_map(|x| x.strip_prefix('"')?.split_once("\"="))
+        .map(|(name, value)| {
+            let value = value.trim_start_matches("str(2):").trim_matches('"');
+            (name.to_string(), value.replace(r"\\", r"\"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty prefix of its own for every test, since they run in parallel
+    fn prefix(test: &str) -> Utf8PathBuf {
+        let path = std::env::temp_dir().join(format!("cellar-{}-{}", test, std::process::id()));
+        let path = Utf8PathBuf::try_from(path).unwrap();
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(path.join("drive_c/users")).unwrap();
+        path
+    }
+
+    fn mapping(target: &str) -> PathMapping {
+        PathMapping {
+            host: "/srv".into(),
+            target: target.parse().unwrap(),
+            read_only: false,
+        }
+    }
+
+    #[test]
+    fn replaces_symlinked_target() {
+        let prefix = prefix("replaces-symlink");
+        let outside = prefix.join("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::create_dir_all(prefix.join("drive_c/users/someone")).unwrap();
+        let documents = prefix.join("drive_c/users/someone/Documents");
+        std::os::unix::fs::symlink(&outside, &documents).unwrap();
+
+        mapping(r"C:\users\someone\Documents")
+            .prepare(&prefix)
+            .unwrap();
+        assert!(documents.symlink_metadata().unwrap().is_dir());
+        assert!(outside.is_dir());
+
+        std::fs::remove_dir_all(prefix).unwrap();
+    }
+
+    #[test]
+    fn refuses_symlinked_ancestors() {
+        let prefix = prefix("refuses-symlink");
+        let outside = prefix.join("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, prefix.join("drive_c/games")).unwrap();
+
+        let result = mapping(r"C:\games\mods").prepare(&prefix);
+        assert!(matches!(result, Err(CellarError::SymlinkInPrefix(x)) if x.ends_with("games")));
+        assert!(!outside.join("mods").exists());
+
+        std::fs::remove_dir_all(prefix).unwrap();
+    }
+
+    #[test]
+    fn picks_profile_by_name() {
+        let prefix = prefix("profile-user");
+        for user in ["Public", "zoe", "alex"] {
+            std::fs::create_dir(prefix.join("drive_c/users").join(user)).unwrap();
+        }
+
+        assert_eq!(profile_user(&prefix).unwrap(), "alex");
+
+        std::fs::remove_dir_all(prefix).unwrap();
+    }
+}
@@ -1,20 +1,24 @@
+use std::fmt;
 use std::io::{self, Write};
 use std::process::Command;
 use std::time::Instant;
 
-use cellar_sandbox::EnvVar;
-use log::info;
+use cellar_sandbox::{resolve_env, EnvVar, LandlockRuleset};
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 pub type Result<T, E = std::io::Error> = std::result::Result<T, E>;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub enum ReaperCommand {
     Execute {
         exec: String,
         args: Vec<String>,
         env: Vec<EnvVar>,
+        /// Applied to the reaper itself before it starts the program, so the program and
+        /// everything it starts inherit it
+        landlock: Option<LandlockRuleset>,
     },
 }
 
@@ -24,6 +28,26 @@ impl ReaperCommand {
     }
 }
 
+/// Leaves out the values of the env vars, which can hold tokens that shouldn't end up in the logs
+impl fmt::Debug for ReaperCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReaperCommand::Execute {
+                exec,
+                args,
+                env,
+                landlock,
+            } => f
+                .debug_struct("Execute")
+                .field("exec", exec)
+                .field("args", args)
+                .field("env", &env.iter().map(EnvVar::key).collect::<Vec<_>>())
+                .field("landlock", landlock)
+                .finish(),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ReaperError {}
 
@@ -52,9 +76,42 @@ fn main() -> Result<()> {
 
     info!("Received Command {:#?}", s);
 
-    match s {
-        ReaperCommand::Execute { exec, args, .. } => {
-            Command::new(exec).args(args).status().unwrap()
+    let status = match s {
+        ReaperCommand::Execute {
+            exec,
+            args,
+            env,
+            landlock,
+        } => {
+            // Refuse to run anything at all rather than run it without the rules it asked for
+            if let Some(ruleset) = landlock {
+                match ruleset.restrict_self() {
+                    Ok(enforcement) => {
+                        info!("Restricted with landlock ABI {}", enforcement.abi);
+                        if ruleset.restricts_network() && !enforcement.network {
+                            warn!("The kernel can't restrict the network with landlock");
+                        }
+                    }
+                    Err(err) => {
+                        error!("Unable to restrict with landlock: {}", err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            let mut cmd = Command::new(exec);
+            cmd.args(args);
+
+            // cellar already looked up everything passed through, so nothing here can be missing
+            let env = resolve_env(env, |x| std::env::var(x).ok()).unwrap();
+            for (key, value) in env {
+                match value {
+                    Some(value) => cmd.env(key, value),
+                    None => cmd.env_remove(key),
+                };
+            }
+
+            cmd.status().unwrap()
         }
     };
 
@@ -62,5 +119,26 @@ fn main() -> Result<()> {
         "Reaper shutting down! Ran for {:?}",
         Instant::now().duration_since(start)
     );
-    Ok(())
+
+    // bwrap passes this on through its status, so cellar can report it
+    std::process::exit(status.code().unwrap_or(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_env_values_out_of_logs() {
+        let cmd = ReaperCommand::Execute {
+            exec: "/usr/bin/wine".into(),
+            args: vec!["game.exe".into()],
+            env: vec![EnvVar::KeyValue("TOKEN".into(), "hunter2".into())],
+            landlock: None,
+        };
+
+        let logged = format!("{:#?}", cmd);
+        assert!(logged.contains("TOKEN") && logged.contains("game.exe"));
+        assert!(!logged.contains("hunter2"));
+    }
 }